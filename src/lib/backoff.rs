@@ -0,0 +1,61 @@
+use rand::Rng;
+use std::time::Duration;
+
+use super::config;
+
+// Exponential backoff with jitter, shared by connect failures, mid-session disconnects and
+// failed database writes
+#[derive(Debug)]
+pub struct Backoff {
+    base: Duration,
+    cap: Duration,
+    jitter: f64,
+    max_attempts: Option<u32>,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(config: &config::ReconnectConfig) -> Self {
+        Self {
+            base: Duration::from_secs(config.base_secs),
+            cap: Duration::from_secs(config.cap_secs),
+            jitter: config.jitter.clamp(0.0, 1.0),
+            max_attempts: config.max_attempts,
+            attempt: 0,
+        }
+    }
+
+    pub fn for_writes(config: &config::WriteRetryConfig) -> Self {
+        Self {
+            base: Duration::from_millis(config.base_ms),
+            cap: Duration::from_secs(config.cap_secs),
+            jitter: config.jitter.clamp(0.0, 1.0),
+            max_attempts: Some(config.max_attempts),
+            attempt: 0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    pub const fn attempts(&self) -> u32 {
+        self.attempt
+    }
+
+    // Returns None once max_attempts is reached, otherwise the delay before the next attempt.
+    // A jitter of 0.5 picks a delay between half and all of the exponential delay.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        if self.max_attempts.is_some_and(|max| self.attempt >= max) {
+            return None;
+        }
+
+        let exponential = self.base.saturating_mul(2_u32.saturating_pow(self.attempt));
+        let delay = exponential.min(self.cap);
+        let factor = 1.0 - self.jitter * rand::thread_rng().gen_range(0.0..1.0);
+
+        self.attempt += 1;
+
+        Some(delay.mul_f64(factor))
+    }
+}
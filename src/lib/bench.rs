@@ -0,0 +1,86 @@
+use log::{info, warn};
+use std::fs;
+use tokio::{sync::watch, time::Instant};
+
+use super::{config, db, error, event, irc, spool, writer};
+
+// Tables are created in this schema and dropped afterwards, so the logs are never touched. The
+// name is unlikely to belong to anyone else, and a schema that already exists is left alone.
+const SCHEMA: &str = "twitch_log_bot_ws_bench";
+
+// Replays a corpus of raw IRC lines, as Twitch sends them, through the writer once per insert
+// method and logs the throughput of each
+pub async fn run(config: &config::Config, corpus: &str) -> Result<(), error::Error> {
+    let data = fs::read_to_string(corpus)?;
+    let events: Vec<event::Event> = irc::split_frame(&data)
+        .filter_map(irc::IrcMessage::parse)
+        .filter_map(|message| event::Event::from_irc(&message))
+        .collect();
+    let batches: Vec<Vec<event::Event>> =
+        events.chunks(config.backpressure.max_batch_size).map(<[event::Event]>::to_vec).collect();
+
+    info!("Replaying {} events from {corpus} in {} batches", events.len(), batches.len());
+
+    let pool = db::create_pool(config, Some(SCHEMA)).await?;
+    let stop = watch::channel(false).0;
+    let exists: bool = pool
+        .get()
+        .await?
+        .query_one("SELECT EXISTS (SELECT 1 FROM pg_namespace WHERE nspname = $1)", &[&SCHEMA])
+        .await?
+        .get(0);
+
+    if exists {
+        return Err(error::Error::Config(format!(
+            "schema {SCHEMA} already exists, drop it first if it is left over from an earlier bench"
+        )));
+    }
+
+    for method in [config::InsertMethod::Insert, config::InsertMethod::Copy] {
+        pool.get().await?.batch_execute(&reset_schema()).await?;
+        db::create_table(&pool).await?;
+
+        let replay = batches.clone();
+        let config = config::Config { insert_method: method, ..config.clone() };
+        // A failed write is lost instead of spooled, so the logs never see bench events
+        let writer =
+            writer::Writer::new(pool.clone(), &config, spool::Spool::disabled(), stop.subscribe());
+        let counters = writer.counters.clone();
+        let (tx, rx) = writer::queue(config.backpressure.channel_capacity);
+        let started = Instant::now();
+        let writer = tokio::spawn(writer.write_batches(rx));
+
+        for batch in replay {
+            counters.received(batch.len());
+
+            if tx.send(batch).await.is_err() {
+                break;
+            }
+        }
+
+        drop(tx);
+
+        if let Err(e) = writer.await {
+            warn!("Writer stopped unexpectedly: {e}");
+        }
+
+        let elapsed = started.elapsed();
+        let stored = events.len() - counters.lost_count() - counters.rejected_count();
+
+        info!(
+            "{method:?}: stored {stored} events in {:.2?} ({:.0} events/s), {} lost, {} rejected",
+            elapsed,
+            stored as f64 / elapsed.as_secs_f64(),
+            counters.lost_count(),
+            counters.rejected_count()
+        );
+    }
+
+    pool.get().await?.batch_execute(&format!("DROP SCHEMA {SCHEMA} CASCADE;")).await?;
+
+    Ok(())
+}
+
+fn reset_schema() -> String {
+    format!("DROP SCHEMA IF EXISTS {SCHEMA} CASCADE; CREATE SCHEMA {SCHEMA};")
+}
@@ -1,19 +1,253 @@
 use std::fs;
 
 use log::{error, info};
+use rand::Rng;
 
 use super::error;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Account {
+    pub nickname: String,
+    pub oauth: String,
+    // Verified bots get much higher join and message limits from Twitch
+    #[serde(default)]
+    pub verified: bool,
+}
+
+// How events are batched for the writer and how many may wait in memory when it falls behind.
+// Every connection has its own batches, channel and buffer.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct BackpressureConfig {
+    // The batch size grows while the writer is behind and shrinks again once it keeps up
+    pub min_batch_size: usize,
+    pub max_batch_size: usize,
+    // Batches handed to the writer that it has not started on yet
+    pub channel_capacity: usize,
+    // Batches a connection inserts at once
+    pub max_concurrent_writes: usize,
+    // Events a connection holds in memory, buffered, queued or being written. on_full decides
+    // what happens to buffered batches beyond that.
+    pub max_buffered_events: usize,
+    pub on_full: OverflowPolicy,
+}
+
+impl Default for BackpressureConfig {
+    fn default() -> Self {
+        Self {
+            min_batch_size: 10,
+            max_batch_size: 50,
+            channel_capacity: 10,
+            max_concurrent_writes: 4,
+            max_buffered_events: 10_000,
+            on_full: OverflowPolicy::Spill,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Config {
+    // Several bot accounts to spread channels over, instead of the single nickname and oauth
+    #[serde(default)]
+    pub accounts: Vec<Account>,
+    // Log in as justinfanNNNNN without a PASS, which can read chat but never send to it
+    #[serde(default)]
+    pub anonymous: bool,
+    #[serde(default)]
+    pub backpressure: BackpressureConfig,
+    // PEM bundle trusted in addition to the system roots, e.g. for a self-signed test server
+    #[serde(default)]
+    pub ca_file: Option<String>,
     pub channels: Vec<String>,
+    // Twitch does not document a per-connection limit, but fewer channels per connection keep a
+    // single disconnect from losing too much
+    #[serde(default = "default_channels_per_connection")]
+    pub channels_per_connection: usize,
+    // Channels of a connection that stays down this long are moved to the healthy ones
+    #[serde(default = "default_failover_after_secs")]
+    pub failover_after_secs: u64,
+    #[serde(default)]
+    pub insert_method: InsertMethod,
+    #[serde(default)]
+    pub log_membership: bool,
+    // Twitch allows a non-verified account to be in 100 channels at once
+    #[serde(default = "default_max_channels_per_account")]
+    pub max_channels_per_account: usize,
+    #[serde(default)]
     pub nickname: String,
+    #[serde(default)]
     pub oauth: String,
+    #[serde(default = "default_ping_interval_secs")]
+    pub ping_interval_secs: u64,
+    #[serde(default = "default_pong_timeout_secs")]
+    pub pong_timeout_secs: u64,
     pub postgres_db: String,
     pub postgres_host: String,
     pub postgres_password: String,
     pub postgres_user: String,
+    #[serde(default)]
+    pub rate_limits: RateLimitConfig,
+    #[serde(default = "default_read_timeout_secs")]
+    pub read_timeout_secs: u64,
+    #[serde(default)]
+    pub reconnect: ReconnectConfig,
     pub server: String,
+    // docker compose stop sends SIGKILL after 10 seconds
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64,
+    #[serde(default)]
+    pub spool: SpoolConfig,
+    #[serde(default)]
+    pub verified: bool,
+    #[serde(default)]
+    pub write_retry: WriteRetryConfig,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FsyncPolicy {
+    // Survives a power loss, at the cost of one fsync per spooled batch
+    Always,
+    // On a write at least fsync_interval_secs after the last sync
+    Interval,
+    // Survives a crash of the bot but not of the machine
+    Never,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum GiveUpPolicy {
+    // Stop the whole process so a supervisor can restart it
+    Exit,
+    // Keep the remaining threads running with fewer channels
+    Continue,
+    // Move the channels of the thread that gave up to the remaining threads
+    Reassign,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum InsertMethod {
+    // Load each batch with binary COPY, which is much faster when big channels burst
+    #[default]
+    Copy,
+    // Send one INSERT per event
+    Insert,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    // Stop reading the connection until the writer takes the oldest batches. Twitch may drop a
+    // connection that stops reading for too long.
+    Block,
+    // Move the oldest batches to the spool, or drop them if it cannot take them
+    Spill,
+    // Drop the oldest batches and count them
+    DropOldest,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub normal: RateLimits,
+    pub verified: RateLimits,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            normal: RateLimits {
+                joins: 20,
+                join_window_secs: 10,
+                commands: 20,
+                command_window_secs: 30,
+            },
+            verified: RateLimits {
+                joins: 2000,
+                join_window_secs: 10,
+                commands: 7500,
+                command_window_secs: 30,
+            },
+        }
+    }
+}
+
+// JOINs are limited separately from every other command sent to the server
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RateLimits {
+    pub joins: u32,
+    pub join_window_secs: u64,
+    pub commands: u32,
+    pub command_window_secs: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ReconnectConfig {
+    pub base_secs: u64,
+    pub cap_secs: u64,
+    pub jitter: f64,
+    // None retries forever
+    pub max_attempts: Option<u32>,
+    pub on_give_up: GiveUpPolicy,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            base_secs: 1,
+            cap_secs: 300,
+            jitter: 0.5,
+            max_attempts: None,
+            on_give_up: GiveUpPolicy::Exit,
+        }
+    }
+}
+
+// File that batches are appended to while Postgres is unreachable or too many are buffered in
+// memory, and stored from once it catches up again
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SpoolConfig {
+    pub enabled: bool,
+    pub path: String,
+    // Events that no longer fit are lost
+    pub max_mb: u64,
+    pub fsync: FsyncPolicy,
+    // Checked when a batch is spooled, there is no timer. The last batches before Postgres comes
+    // back can stay unsynced until they are drained, so a power loss in between can lose them.
+    pub fsync_interval_secs: u64,
+}
+
+impl Default for SpoolConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            path: "spool.jsonl".to_string(),
+            max_mb: 1024,
+            fsync: FsyncPolicy::Interval,
+            fsync_interval_secs: 1,
+        }
+    }
+}
+
+// Backoff for batches that failed with an error that may go away, like a reset connection.
+// Batches Postgres refuses outright are split up instead, to find the events it will not take.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct WriteRetryConfig {
+    pub base_ms: u64,
+    pub cap_secs: u64,
+    pub jitter: f64,
+    // The batch counts as lost after this many retries, so a long outage cannot pile up memory
+    pub max_attempts: u32,
+}
+
+impl Default for WriteRetryConfig {
+    fn default() -> Self {
+        Self { base_ms: 250, cap_secs: 30, jitter: 0.5, max_attempts: 8 }
+    }
 }
 
 impl Config {
@@ -28,7 +262,7 @@ impl Config {
                 return Err(error::Error::Io(e));
             }
         };
-        let json: Self = match serde_json::from_reader(file) {
+        let mut config: Self = match serde_json::from_reader(file) {
             Ok(json) => {
                 info!("JSON file parsed successfully");
                 json
@@ -39,6 +273,187 @@ impl Config {
             }
         };
 
-        Ok(json)
+        if let Err(e) = config.validate() {
+            error!("{e}");
+            return Err(e);
+        }
+
+        if config.anonymous && config.nickname.is_empty() {
+            config.nickname = format!("justinfan{}", rand::thread_rng().gen_range(10000..100_000));
+        }
+
+        Ok(config)
+    }
+
+    // The configured accounts, or the single nickname and oauth when no list is given
+    pub fn accounts(&self) -> Vec<Account> {
+        if self.accounts.is_empty() {
+            vec![Account {
+                nickname: self.nickname.clone(),
+                oauth: self.oauth.clone(),
+                verified: self.verified,
+            }]
+        } else {
+            self.accounts.clone()
+        }
+    }
+
+    pub const fn rate_limits(&self, verified: bool) -> &RateLimits {
+        if verified {
+            &self.rate_limits.verified
+        } else {
+            &self.rate_limits.normal
+        }
+    }
+
+    // Refuses settings that cannot work, such as ones that need a real account while anonymous
+    fn validate(&self) -> Result<(), error::Error> {
+        if self.anonymous {
+            if !self.oauth.is_empty() {
+                return Err(error::Error::Config(
+                    "oauth must be empty when anonymous is true".to_string(),
+                ));
+            }
+            if !self.nickname.is_empty() && !self.nickname.starts_with("justinfan") {
+                return Err(error::Error::Config(
+                    "nickname must be empty or justinfanNNNNN when anonymous is true".to_string(),
+                ));
+            }
+            if self.verified || !self.accounts.is_empty() {
+                return Err(error::Error::Config(
+                    "verified and accounts require a bot account, not anonymous".to_string(),
+                ));
+            }
+        } else if self.accounts.is_empty() {
+            if self.nickname.is_empty() || self.oauth.is_empty() {
+                return Err(error::Error::Config(
+                    "nickname and oauth are required unless anonymous is true".to_string(),
+                ));
+            }
+        } else {
+            if !self.nickname.is_empty() || !self.oauth.is_empty() {
+                return Err(error::Error::Config(
+                    "use either accounts or nickname and oauth, not both".to_string(),
+                ));
+            }
+
+            for (i, account) in self.accounts.iter().enumerate() {
+                if account.nickname.is_empty() || account.oauth.is_empty() {
+                    return Err(error::Error::Config(
+                        "every account needs a nickname and oauth".to_string(),
+                    ));
+                }
+                if self.accounts[..i].iter().any(|x| x.nickname == account.nickname) {
+                    return Err(error::Error::Config(format!(
+                        "account {} is listed more than once",
+                        account.nickname
+                    )));
+                }
+            }
+        }
+
+        let backpressure = &self.backpressure;
+
+        if backpressure.min_batch_size == 0
+            || backpressure.max_batch_size < backpressure.min_batch_size
+            || backpressure.channel_capacity == 0
+            || backpressure.max_concurrent_writes == 0
+        {
+            return Err(error::Error::Config(
+                "backpressure needs a min_batch_size, channel_capacity and max_concurrent_writes \
+                 above zero and a max_batch_size of at least min_batch_size"
+                    .to_string(),
+            ));
+        }
+
+        if self.channels_per_connection == 0 {
+            return Err(error::Error::Config(
+                "channels_per_connection must be above zero".to_string(),
+            ));
+        }
+
+        if self.max_channels_per_account == 0 {
+            return Err(error::Error::Config(
+                "max_channels_per_account must be above zero".to_string(),
+            ));
+        }
+
+        for limits in [&self.rate_limits.normal, &self.rate_limits.verified] {
+            if limits.joins == 0
+                || limits.join_window_secs == 0
+                || limits.commands == 0
+                || limits.command_window_secs == 0
+            {
+                return Err(error::Error::Config("rate_limits must all be above zero".to_string()));
+            }
+        }
+
+        if self.ping_interval_secs == 0
+            || self.pong_timeout_secs == 0
+            || self.read_timeout_secs == 0
+        {
+            return Err(error::Error::Config(
+                "ping_interval_secs, pong_timeout_secs and read_timeout_secs must be above zero"
+                    .to_string(),
+            ));
+        }
+
+        // Otherwise an idle but healthy connection times out before its PONG can arrive
+        if self.read_timeout_secs <= self.ping_interval_secs + self.pong_timeout_secs {
+            return Err(error::Error::Config(
+                "read_timeout_secs must be longer than ping_interval_secs plus pong_timeout_secs"
+                    .to_string(),
+            ));
+        }
+
+        let reconnect = &self.reconnect;
+
+        if reconnect.base_secs == 0
+            || reconnect.cap_secs < reconnect.base_secs
+            || !(0.0..=1.0).contains(&reconnect.jitter)
+        {
+            return Err(error::Error::Config(
+                "reconnect needs a base_secs above zero, a cap_secs of at least base_secs and a \
+                 jitter between 0 and 1"
+                    .to_string(),
+            ));
+        }
+
+        if self.spool.enabled && (self.spool.path.is_empty() || self.spool.max_mb == 0) {
+            return Err(error::Error::Config(
+                "spool needs a path and a max_mb above zero".to_string(),
+            ));
+        }
+
+        Ok(())
     }
 }
+
+const fn default_channels_per_connection() -> usize {
+    50
+}
+
+const fn default_failover_after_secs() -> u64 {
+    120
+}
+
+const fn default_max_channels_per_account() -> usize {
+    100
+}
+
+const fn default_ping_interval_secs() -> u64 {
+    60
+}
+
+const fn default_pong_timeout_secs() -> u64 {
+    10
+}
+
+// Twitch sends its own PING roughly every five minutes
+const fn default_read_timeout_secs() -> u64 {
+    360
+}
+
+const fn default_shutdown_timeout_secs() -> u64 {
+    8
+}
@@ -0,0 +1,63 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Events that were read but not stored yet, events that could not be stored at all, events
+// dropped because too many were buffered, and events Postgres refused outright. Shared by every
+// thread so shutdown can report what was lost.
+#[derive(Debug, Default)]
+pub struct EventCounters {
+    pending: AtomicUsize,
+    lost: AtomicUsize,
+    dropped: AtomicUsize,
+    rejected: AtomicUsize,
+}
+
+impl EventCounters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn received(&self, count: usize) {
+        self.pending.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn stored(&self, count: usize) {
+        self.pending.fetch_sub(count, Ordering::Relaxed);
+    }
+
+    pub fn lost(&self, count: usize) {
+        self.pending.fetch_sub(count, Ordering::Relaxed);
+        self.lost.fetch_add(count, Ordering::Relaxed);
+    }
+
+    // Dropped events also count as lost
+    pub fn dropped(&self, count: usize) {
+        self.lost(count);
+        self.dropped.fetch_add(count, Ordering::Relaxed);
+    }
+
+    // Spooled events are safe on disk and pending again once they are read back
+    pub fn spooled(&self, count: usize) {
+        self.pending.fetch_sub(count, Ordering::Relaxed);
+    }
+
+    pub fn rejected(&self, count: usize) {
+        self.pending.fetch_sub(count, Ordering::Relaxed);
+        self.rejected.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn pending_count(&self) -> usize {
+        self.pending.load(Ordering::Relaxed)
+    }
+
+    pub fn lost_count(&self) -> usize {
+        self.lost.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped_count(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn rejected_count(&self) -> usize {
+        self.rejected.load(Ordering::Relaxed)
+    }
+}
@@ -1,13 +1,23 @@
 use bb8::Pool;
 use bb8_postgres::PostgresConnectionManager;
 use log::{debug, error, info, warn};
+use std::{collections::HashMap, pin::pin};
 use tokio::time::Duration;
-use tokio_postgres::NoTls;
+use tokio_postgres::{
+    binary_copy::BinaryCopyInWriter,
+    error::SqlState,
+    types::{ToSql, Type},
+    NoTls, Statement, Transaction,
+};
 
 use super::{config, error, event};
 
+const STATEMENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// `schema` puts every table of the pool's connections in that schema instead of public
 pub async fn create_pool(
     secrets: &config::Config,
+    schema: Option<&str>,
 ) -> Result<Pool<PostgresConnectionManager<NoTls>>, error::Error> {
     let mut config = tokio_postgres::Config::new();
 
@@ -17,6 +27,10 @@ pub async fn create_pool(
         .password(&secrets.postgres_password)
         .dbname(&secrets.postgres_db);
 
+    if let Some(schema) = schema {
+        config.options(format!("-c search_path={schema}").as_str());
+    }
+
     let manager = PostgresConnectionManager::new(config, NoTls);
 
     match Pool::builder()
@@ -36,14 +50,17 @@ pub async fn create_pool(
     }
 }
 
+// Loggers that start together take turns, so they cannot race each other creating the same tables
+// and indexes
 pub async fn create_table(
     pool: &Pool<PostgresConnectionManager<NoTls>>,
 ) -> Result<(), error::Error> {
     match pool.get().await {
         Ok(conn) => {
             match conn
-                .execute(
-                    "CREATE TABLE IF NOT EXISTS logs (
+                .batch_execute(
+                    "SELECT pg_advisory_xact_lock(hashtext('twitch-log-bot-ws create_table'));
+                    CREATE TABLE IF NOT EXISTS logs (
                         id SERIAL PRIMARY KEY,
                         username VARCHAR,
                         command VARCHAR,
@@ -74,18 +91,201 @@ pub async fn create_table(
                         user_id VARCHAR,
                         user_type VARCHAR,
                         vip VARCHAR,
+                        timestamp TIMESTAMP WITH TIME ZONE,
+                        msg_id VARCHAR
+                    );
+                    ALTER TABLE logs ADD COLUMN IF NOT EXISTS msg_id VARCHAR;
+                    DO $$
+                    DECLARE
+                        removed BIGINT;
+                    BEGIN
+                        IF to_regclass('logs_msg_id_key') IS NULL THEN
+                            UPDATE logs SET msg_id = NULL WHERE msg_id = '';
+                            DELETE FROM logs a USING logs b
+                                WHERE a.msg_id = b.msg_id AND a.id > b.id;
+                            GET DIAGNOSTICS removed = ROW_COUNT;
+                            IF removed > 0 THEN
+                                RAISE WARNING 'Removed % duplicate rows from logs to make msg_id unique',
+                                    removed;
+                            END IF;
+                            CREATE UNIQUE INDEX IF NOT EXISTS logs_msg_id_key ON logs (msg_id);
+                        END IF;
+                    END $$;
+                    DROP INDEX IF EXISTS logs_msg_id_idx;
+                    CREATE TABLE IF NOT EXISTS clearchat (
+                        id SERIAL PRIMARY KEY,
+                        channel VARCHAR,
+                        target_login VARCHAR,
+                        ban_duration INTEGER,
+                        room_id VARCHAR,
+                        target_user_id VARCHAR,
+                        tags_raw VARCHAR,
+                        tmi_sent_ts VARCHAR,
+                        timestamp TIMESTAMP WITH TIME ZONE
+                    );
+                    CREATE TABLE IF NOT EXISTS clearmsg (
+                        id SERIAL PRIMARY KEY,
+                        channel VARCHAR,
+                        content VARCHAR,
+                        login VARCHAR,
+                        room_id VARCHAR,
+                        tags_raw VARCHAR,
+                        target_msg_id VARCHAR,
+                        tmi_sent_ts VARCHAR,
+                        timestamp TIMESTAMP WITH TIME ZONE
+                    );
+                    CREATE TABLE IF NOT EXISTS usernotice (
+                        id SERIAL PRIMARY KEY,
+                        channel VARCHAR,
+                        content VARCHAR,
+                        badges VARCHAR,
+                        color VARCHAR,
+                        display_name VARCHAR,
+                        msg_id VARCHAR,
+                        login VARCHAR,
+                        notice_type VARCHAR,
+                        room_id VARCHAR,
+                        system_msg VARCHAR,
+                        user_id VARCHAR,
+                        cumulative_months INTEGER,
+                        gift_months INTEGER,
+                        mass_gift_count INTEGER,
+                        months INTEGER,
+                        raid_display_name VARCHAR,
+                        raid_login VARCHAR,
+                        raid_viewer_count INTEGER,
+                        recipient_display_name VARCHAR,
+                        recipient_id VARCHAR,
+                        recipient_user_name VARCHAR,
+                        sender_count INTEGER,
+                        should_share_streak INTEGER,
+                        streak_months INTEGER,
+                        sub_plan VARCHAR,
+                        sub_plan_name VARCHAR,
+                        msg_params JSONB,
+                        tags_raw VARCHAR,
+                        tmi_sent_ts VARCHAR,
+                        timestamp TIMESTAMP WITH TIME ZONE
+                    );
+                    CREATE INDEX IF NOT EXISTS usernotice_channel_type_idx
+                        ON usernotice (channel, notice_type);
+                    DO $$
+                    DECLARE
+                        removed BIGINT;
+                    BEGIN
+                        IF to_regclass('usernotice_msg_id_key') IS NULL THEN
+                            UPDATE usernotice SET msg_id = NULL WHERE msg_id = '';
+                            DELETE FROM usernotice a USING usernotice b
+                                WHERE a.msg_id = b.msg_id AND a.id > b.id;
+                            GET DIAGNOSTICS removed = ROW_COUNT;
+                            IF removed > 0 THEN
+                                RAISE WARNING 'Removed % duplicate rows from usernotice to make msg_id unique',
+                                    removed;
+                            END IF;
+                            CREATE UNIQUE INDEX IF NOT EXISTS usernotice_msg_id_key ON usernotice (msg_id);
+                        END IF;
+                    END $$;
+                    CREATE TABLE IF NOT EXISTS membership (
+                        id SERIAL PRIMARY KEY,
+                        channel VARCHAR,
+                        command VARCHAR,
+                        login VARCHAR,
+                        timestamp TIMESTAMP WITH TIME ZONE,
+                        is_self BOOLEAN NOT NULL DEFAULT false
+                    );
+                    ALTER TABLE membership
+                        ADD COLUMN IF NOT EXISTS is_self BOOLEAN NOT NULL DEFAULT false;
+                    CREATE INDEX IF NOT EXISTS membership_channel_login_timestamp_idx
+                        ON membership (channel, login, timestamp);
+                    CREATE INDEX IF NOT EXISTS membership_self_idx
+                        ON membership (channel, timestamp) WHERE is_self;
+                    CREATE TABLE IF NOT EXISTS notice (
+                        id SERIAL PRIMARY KEY,
+                        channel VARCHAR,
+                        content VARCHAR,
+                        notice_type VARCHAR,
+                        target_user_id VARCHAR,
+                        tags_raw VARCHAR,
+                        timestamp TIMESTAMP WITH TIME ZONE
+                    );
+                    CREATE TABLE IF NOT EXISTS roomstate (
+                        id SERIAL PRIMARY KEY,
+                        channel VARCHAR,
+                        room_id VARCHAR,
+                        emote_only INTEGER,
+                        followers_only INTEGER,
+                        r9k INTEGER,
+                        slow INTEGER,
+                        subs_only INTEGER,
+                        tags_raw VARCHAR,
                         timestamp TIMESTAMP WITH TIME ZONE
-                    );",
-                    &[],
+                    );
+                    CREATE INDEX IF NOT EXISTS roomstate_room_id_timestamp_idx
+                        ON roomstate (room_id, timestamp);
+                    CREATE TABLE IF NOT EXISTS shard_assignments (
+                        channel VARCHAR PRIMARY KEY,
+                        thread_id BIGINT NOT NULL,
+                        account VARCHAR
+                    );
+                    ALTER TABLE shard_assignments ADD COLUMN IF NOT EXISTS account VARCHAR;
+                    CREATE OR REPLACE VIEW deleted_messages AS
+                        SELECT
+                            clearmsg.id AS clearmsg_id,
+                            logs.id AS log_id,
+                            clearmsg.channel,
+                            clearmsg.login,
+                            clearmsg.content,
+                            clearmsg.target_msg_id,
+                            logs.timestamp AS sent_at,
+                            clearmsg.timestamp AS deleted_at
+                        FROM clearmsg
+                        LEFT JOIN logs ON logs.msg_id = clearmsg.target_msg_id;
+                    CREATE OR REPLACE VIEW logs_roomstate AS
+                        SELECT
+                            logs.id AS log_id,
+                            logs.channel,
+                            logs.timestamp,
+                            roomstate.emote_only,
+                            roomstate.followers_only,
+                            roomstate.r9k,
+                            roomstate.slow,
+                            roomstate.subs_only
+                        FROM logs
+                        LEFT JOIN LATERAL (
+                            SELECT * FROM roomstate
+                            WHERE roomstate.room_id = logs.room_id
+                                AND roomstate.timestamp <= logs.timestamp
+                            ORDER BY roomstate.timestamp DESC
+                            LIMIT 1
+                        ) roomstate ON true;
+                    CREATE OR REPLACE VIEW present_chatters AS
+                        WITH session AS (
+                            SELECT DISTINCT ON (channel) channel, command, timestamp
+                            FROM membership
+                            WHERE is_self
+                            ORDER BY channel, timestamp DESC
+                        )
+                        SELECT channel, login, timestamp AS joined_at
+                        FROM (
+                            SELECT DISTINCT ON (membership.channel, login)
+                                membership.channel, login, membership.command, membership.timestamp
+                            FROM membership
+                            JOIN session ON session.channel = membership.channel
+                                AND session.command = 'JOIN'
+                                AND membership.timestamp >= session.timestamp
+                            WHERE NOT is_self
+                            ORDER BY membership.channel, login, membership.timestamp DESC
+                        ) latest
+                        WHERE command = 'JOIN';",
                 )
                 .await
             {
-                Ok(_) => {
-                    info!("Postgres table created successfully or already exists");
+                Ok(()) => {
+                    info!("Postgres tables created successfully or already exist");
                     Ok(())
                 }
                 Err(e) => {
-                    error!("Error creating Postgres table: {e}");
+                    error!("Error creating Postgres tables: {e}");
                     Err(error::Error::Postgres(e))
                 }
             }
@@ -97,9 +297,405 @@ pub async fn create_table(
     }
 }
 
+// Which thread and account each channel was on when the bot last ran, so restarts keep the same
+// layout
+pub async fn load_assignments(
+    pool: &Pool<PostgresConnectionManager<NoTls>>,
+) -> Result<HashMap<String, (u32, String)>, error::Error> {
+    let conn = match pool.get().await {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Error retrieving connection from pool: {e}");
+            return Err(error::Error::bb8(e));
+        }
+    };
+
+    match conn.query("SELECT channel, thread_id, account FROM shard_assignments", &[]).await {
+        Ok(rows) => Ok(rows
+            .iter()
+            .filter_map(|row| {
+                let thread_id = u32::try_from(row.get::<_, i64>(1)).ok()?;
+                let account: Option<String> = row.get(2);
+
+                Some((row.get(0), (thread_id, account.unwrap_or_default())))
+            })
+            .collect()),
+        Err(e) => {
+            warn!("Error loading shard assignments: {e}");
+            Err(error::Error::Postgres(e))
+        }
+    }
+}
+
+pub async fn save_assignments(
+    pool: &Pool<PostgresConnectionManager<NoTls>>,
+    assignments: &[(String, u32, String)],
+) -> Result<(), error::Error> {
+    let mut conn = match pool.get().await {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Error retrieving connection from pool: {e}");
+            return Err(error::Error::bb8(e));
+        }
+    };
+    let transaction = conn.transaction().await?;
+
+    transaction.execute("DELETE FROM shard_assignments", &[]).await?;
+
+    let stmt = prepare(
+        &transaction,
+        "INSERT INTO shard_assignments (channel, thread_id, account) VALUES ($1, $2, $3)",
+    )
+    .await?;
+
+    for (channel, thread_id, account) in assignments {
+        execute(&transaction, &stmt, &[channel, &i64::from(*thread_id), account]).await?;
+    }
+
+    transaction.commit().await?;
+    debug!("Saved {} shard assignments", assignments.len());
+
+    Ok(())
+}
+
+// Columns of a log table, in the order `row` returns their values
+struct Table {
+    name: &'static str,
+    columns: &'static [(&'static str, Type)],
+    // Messages whose id is already stored are skipped
+    unique_msg_id: bool,
+}
+
+impl Table {
+    fn column_list(&self) -> String {
+        self.columns.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(", ")
+    }
+
+    // Twitch leaves the id empty on some events, which is stored as NULL so it never conflicts
+    fn value_list(&self, value: impl Fn(usize, &str) -> String) -> String {
+        self.columns
+            .iter()
+            .enumerate()
+            .map(|(i, (name, _))| {
+                let value = value(i, name);
+                if *name == "msg_id" {
+                    format!("NULLIF({value}, '')")
+                } else {
+                    value
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    fn on_conflict(&self) -> &'static str {
+        if self.unique_msg_id {
+            " ON CONFLICT (msg_id) DO NOTHING"
+        } else {
+            ""
+        }
+    }
+
+    fn insert_query(&self) -> String {
+        format!(
+            "INSERT INTO {} ({}) VALUES ({}){};",
+            self.name,
+            self.column_list(),
+            self.value_list(|i, _| format!("${}", i + 1)),
+            self.on_conflict()
+        )
+    }
+}
+
+const CLEARCHAT: Table = Table {
+    name: "clearchat",
+    columns: &[
+        ("channel", Type::VARCHAR),
+        ("target_login", Type::VARCHAR),
+        ("ban_duration", Type::INT4),
+        ("room_id", Type::VARCHAR),
+        ("target_user_id", Type::VARCHAR),
+        ("tags_raw", Type::VARCHAR),
+        ("tmi_sent_ts", Type::VARCHAR),
+        ("timestamp", Type::TIMESTAMPTZ),
+    ],
+    unique_msg_id: false,
+};
+
+const CLEARMSG: Table = Table {
+    name: "clearmsg",
+    columns: &[
+        ("channel", Type::VARCHAR),
+        ("content", Type::VARCHAR),
+        ("login", Type::VARCHAR),
+        ("room_id", Type::VARCHAR),
+        ("tags_raw", Type::VARCHAR),
+        ("target_msg_id", Type::VARCHAR),
+        ("tmi_sent_ts", Type::VARCHAR),
+        ("timestamp", Type::TIMESTAMPTZ),
+    ],
+    unique_msg_id: false,
+};
+
+const LOGS: Table = Table {
+    name: "logs",
+    columns: &[
+        ("username", Type::VARCHAR),
+        ("command", Type::VARCHAR),
+        ("channel", Type::VARCHAR),
+        ("content", Type::VARCHAR),
+        ("badge_info", Type::VARCHAR),
+        ("badges", Type::VARCHAR),
+        ("bits", Type::VARCHAR),
+        ("client_nonce", Type::VARCHAR),
+        ("color", Type::VARCHAR),
+        ("display_name", Type::VARCHAR),
+        ("emote_only", Type::VARCHAR),
+        ("emotes", Type::VARCHAR),
+        ("first_msg", Type::INT4),
+        ("flags", Type::VARCHAR),
+        ("is_mod", Type::INT4),
+        ("reply_parent_display_name", Type::VARCHAR),
+        ("reply_parent_msg_body", Type::VARCHAR),
+        ("reply_parent_msg_id", Type::VARCHAR),
+        ("reply_parent_user_id", Type::VARCHAR),
+        ("reply_parent_user_login", Type::VARCHAR),
+        ("returning_chatter", Type::INT4),
+        ("room_id", Type::VARCHAR),
+        ("subscriber", Type::INT4),
+        ("tags_raw", Type::VARCHAR),
+        ("tmi_sent_ts", Type::VARCHAR),
+        ("turbo", Type::INT4),
+        ("user_id", Type::VARCHAR),
+        ("user_type", Type::VARCHAR),
+        ("vip", Type::VARCHAR),
+        ("timestamp", Type::TIMESTAMPTZ),
+        ("msg_id", Type::VARCHAR),
+    ],
+    unique_msg_id: true,
+};
+
+const MEMBERSHIP: Table = Table {
+    name: "membership",
+    columns: &[
+        ("channel", Type::VARCHAR),
+        ("command", Type::VARCHAR),
+        ("login", Type::VARCHAR),
+        ("timestamp", Type::TIMESTAMPTZ),
+        ("is_self", Type::BOOL),
+    ],
+    unique_msg_id: false,
+};
+
+const NOTICE: Table = Table {
+    name: "notice",
+    columns: &[
+        ("channel", Type::VARCHAR),
+        ("content", Type::VARCHAR),
+        ("notice_type", Type::VARCHAR),
+        ("target_user_id", Type::VARCHAR),
+        ("tags_raw", Type::VARCHAR),
+        ("timestamp", Type::TIMESTAMPTZ),
+    ],
+    unique_msg_id: false,
+};
+
+const ROOMSTATE: Table = Table {
+    name: "roomstate",
+    columns: &[
+        ("channel", Type::VARCHAR),
+        ("room_id", Type::VARCHAR),
+        ("emote_only", Type::INT4),
+        ("followers_only", Type::INT4),
+        ("r9k", Type::INT4),
+        ("slow", Type::INT4),
+        ("subs_only", Type::INT4),
+        ("tags_raw", Type::VARCHAR),
+        ("timestamp", Type::TIMESTAMPTZ),
+    ],
+    unique_msg_id: false,
+};
+
+const USERNOTICE: Table = Table {
+    name: "usernotice",
+    columns: &[
+        ("channel", Type::VARCHAR),
+        ("content", Type::VARCHAR),
+        ("badges", Type::VARCHAR),
+        ("color", Type::VARCHAR),
+        ("display_name", Type::VARCHAR),
+        ("msg_id", Type::VARCHAR),
+        ("login", Type::VARCHAR),
+        ("notice_type", Type::VARCHAR),
+        ("room_id", Type::VARCHAR),
+        ("system_msg", Type::VARCHAR),
+        ("user_id", Type::VARCHAR),
+        ("cumulative_months", Type::INT4),
+        ("gift_months", Type::INT4),
+        ("mass_gift_count", Type::INT4),
+        ("months", Type::INT4),
+        ("raid_display_name", Type::VARCHAR),
+        ("raid_login", Type::VARCHAR),
+        ("raid_viewer_count", Type::INT4),
+        ("recipient_display_name", Type::VARCHAR),
+        ("recipient_id", Type::VARCHAR),
+        ("recipient_user_name", Type::VARCHAR),
+        ("sender_count", Type::INT4),
+        ("should_share_streak", Type::INT4),
+        ("streak_months", Type::INT4),
+        ("sub_plan", Type::VARCHAR),
+        ("sub_plan_name", Type::VARCHAR),
+        ("msg_params", Type::JSONB),
+        ("tags_raw", Type::VARCHAR),
+        ("tmi_sent_ts", Type::VARCHAR),
+        ("timestamp", Type::TIMESTAMPTZ),
+    ],
+    unique_msg_id: true,
+};
+
+type Row<'a> = Vec<&'a (dyn ToSql + Sync)>;
+
+fn row(event: &event::Event) -> (&'static Table, Row<'_>) {
+    match event {
+        event::Event::ClearChat(clearchat) => (
+            &CLEARCHAT,
+            vec![
+                &clearchat.channel,
+                &clearchat.target_login,
+                &clearchat.ban_duration,
+                &clearchat.room_id,
+                &clearchat.target_user_id,
+                &clearchat.tags_raw,
+                &clearchat.tmi_sent_ts,
+                &clearchat.timestamp,
+            ],
+        ),
+        event::Event::ClearMsg(clearmsg) => (
+            &CLEARMSG,
+            vec![
+                &clearmsg.channel,
+                &clearmsg.content,
+                &clearmsg.login,
+                &clearmsg.room_id,
+                &clearmsg.tags_raw,
+                &clearmsg.target_msg_id,
+                &clearmsg.tmi_sent_ts,
+                &clearmsg.timestamp,
+            ],
+        ),
+        event::Event::Membership(membership) => (
+            &MEMBERSHIP,
+            vec![
+                &membership.channel,
+                &membership.command,
+                &membership.login,
+                &membership.timestamp,
+                &membership.is_self,
+            ],
+        ),
+        event::Event::Notice(notice) => (
+            &NOTICE,
+            vec![
+                &notice.channel,
+                &notice.content,
+                &notice.notice_type,
+                &notice.target_user_id,
+                &notice.tags_raw,
+                &notice.timestamp,
+            ],
+        ),
+        event::Event::Privmsg { msg, tags } => (
+            &LOGS,
+            vec![
+                &msg.username,
+                &msg.command,
+                &msg.channel,
+                &msg.content,
+                &tags.badge_info,
+                &tags.badges,
+                &tags.bits,
+                &tags.client_nonce,
+                &tags.color,
+                &tags.display_name,
+                &tags.emote_only,
+                &tags.emotes,
+                &tags.first_msg,
+                &tags.flags,
+                &tags.is_mod,
+                &tags.reply_parent_display_name,
+                &tags.reply_parent_msg_body,
+                &tags.reply_parent_msg_id,
+                &tags.reply_parent_user_id,
+                &tags.reply_parent_user_login,
+                &tags.returning_chatter,
+                &tags.room_id,
+                &tags.subscriber,
+                &tags.tags_raw,
+                &tags.tmi_sent_ts,
+                &tags.turbo,
+                &tags.user_id,
+                &tags.user_type,
+                &tags.vip,
+                &msg.timestamp,
+                &tags.id,
+            ],
+        ),
+        event::Event::RoomState(roomstate) => (
+            &ROOMSTATE,
+            vec![
+                &roomstate.channel,
+                &roomstate.room_id,
+                &roomstate.emote_only,
+                &roomstate.followers_only,
+                &roomstate.r9k,
+                &roomstate.slow,
+                &roomstate.subs_only,
+                &roomstate.tags_raw,
+                &roomstate.timestamp,
+            ],
+        ),
+        event::Event::UserNotice(usernotice) => (
+            &USERNOTICE,
+            vec![
+                &usernotice.channel,
+                &usernotice.content,
+                &usernotice.badges,
+                &usernotice.color,
+                &usernotice.display_name,
+                &usernotice.id,
+                &usernotice.login,
+                &usernotice.notice_type,
+                &usernotice.room_id,
+                &usernotice.system_msg,
+                &usernotice.user_id,
+                &usernotice.cumulative_months,
+                &usernotice.gift_months,
+                &usernotice.mass_gift_count,
+                &usernotice.months,
+                &usernotice.raid_display_name,
+                &usernotice.raid_login,
+                &usernotice.raid_viewer_count,
+                &usernotice.recipient_display_name,
+                &usernotice.recipient_id,
+                &usernotice.recipient_user_name,
+                &usernotice.sender_count,
+                &usernotice.should_share_streak,
+                &usernotice.streak_months,
+                &usernotice.sub_plan,
+                &usernotice.sub_plan_name,
+                &usernotice.msg_params,
+                &usernotice.tags_raw,
+                &usernotice.tmi_sent_ts,
+                &usernotice.timestamp,
+            ],
+        ),
+    }
+}
+
 pub async fn insert_data(
     pool: Pool<PostgresConnectionManager<NoTls>>,
-    events: Vec<event::Event>,
+    events: &[event::Event],
+    method: config::InsertMethod,
 ) -> Result<(), error::Error> {
     match pool.get().await {
         Ok(mut conn) => {
@@ -113,101 +709,22 @@ pub async fn insert_data(
                     return Err(error::Error::Postgres(e));
                 }
             };
-            let statement = match transaction.prepare("INSERT INTO logs (
-                username,
-                command,
-                channel,
-                content,
-                badge_info,
-                badges,
-                bits,
-                client_nonce,
-                color,
-                display_name,
-                emote_only,
-                emotes,
-                first_msg,
-                flags,
-                is_mod,
-                reply_parent_display_name,
-                reply_parent_msg_body,
-                reply_parent_msg_id,
-                reply_parent_user_id,
-                reply_parent_user_login,
-                returning_chatter,
-                room_id,
-                subscriber,
-                tags_raw,
-                tmi_sent_ts,
-                turbo,
-                user_id,
-                user_type,
-                vip,
-                timestamp
-            ) VALUES (
-                $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19, $20, $21, $22, $23, $24, $25, $26, $27, $28, $29, $30
-            );").await {
-                Ok(statement) => {
-                    debug!("Postgres statement prepared successfully");
-                    statement
-                },
-                Err(e) => {
-                    warn!("Error preparing Postgres statement: {e}");
-                    return Err(error::Error::Postgres(e));
-                }
-            };
-
-            for event in events {
-                let result = tokio::time::timeout(
-                    Duration::from_secs(10),
-                    transaction.execute(
-                        &statement,
-                        &[
-                            &event.msg.username,
-                            &event.msg.command,
-                            &event.msg.channel,
-                            &event.msg.content,
-                            &event.tags.badge_info,
-                            &event.tags.badges,
-                            &event.tags.bits,
-                            &event.tags.client_nonce,
-                            &event.tags.color,
-                            &event.tags.display_name,
-                            &event.tags.emote_only,
-                            &event.tags.emotes,
-                            &event.tags.first_msg,
-                            &event.tags.flags,
-                            &event.tags.is_mod,
-                            &event.tags.reply_parent_display_name,
-                            &event.tags.reply_parent_msg_body,
-                            &event.tags.reply_parent_msg_id,
-                            &event.tags.reply_parent_user_id,
-                            &event.tags.reply_parent_user_login,
-                            &event.tags.returning_chatter,
-                            &event.tags.room_id,
-                            &event.tags.subscriber,
-                            &event.tags.tags_raw,
-                            &event.tags.tmi_sent_ts,
-                            &event.tags.turbo,
-                            &event.tags.user_id,
-                            &event.tags.user_type,
-                            &event.tags.vip,
-                            &event.msg.timestamp,
-                        ],
-                    ),
-                )
-                .await;
+            let rows: Vec<_> = events.iter().map(row).collect();
 
-                match result {
-                    Ok(Ok(_)) => debug!("Postgres statement executed successfully"),
-                    Ok(Err(e)) => {
-                        warn!("Error executing Postgres statement: {e}");
-                        return Err(error::Error::Postgres(e));
+            match method {
+                // A timeout drops the transaction, which rolls it back
+                config::InsertMethod::Copy => {
+                    match tokio::time::timeout(STATEMENT_TIMEOUT, copy_rows(&transaction, rows))
+                        .await
+                    {
+                        Ok(result) => result?,
+                        Err(e) => {
+                            warn!("Timeout occurred while copying Postgres rows: {e}");
+                            return Err(error::Error::StatementTimeout);
+                        }
                     }
-                    Err(e) => {
-                        warn!("Timeout occurred while executing Postgres statement: {e}");
-                    }
-                };
+                }
+                config::InsertMethod::Insert => insert_rows(&transaction, rows).await?,
             }
 
             match transaction.commit().await {
@@ -227,3 +744,169 @@ pub async fn insert_data(
         }
     }
 }
+
+async fn insert_rows(
+    transaction: &Transaction<'_>,
+    rows: Vec<(&Table, Row<'_>)>,
+) -> Result<(), error::Error> {
+    let mut statements: HashMap<&str, Statement> = HashMap::new();
+
+    for (table, values) in rows {
+        let statement = match statements.get(table.name) {
+            Some(statement) => statement.clone(),
+            None => {
+                let statement = prepare(transaction, &table.insert_query()).await?;
+                statements.insert(table.name, statement.clone());
+                statement
+            }
+        };
+
+        execute(transaction, &statement, &values).await?;
+    }
+
+    Ok(())
+}
+
+// Loads each table with a single binary COPY. COPY cannot skip duplicates, so tables with unique
+// message ids are copied into a temporary table first and inserted from there.
+async fn copy_rows(
+    transaction: &Transaction<'_>,
+    rows: Vec<(&Table, Row<'_>)>,
+) -> Result<(), error::Error> {
+    let mut tables: Vec<(&Table, Vec<Row>)> = Vec::new();
+
+    for (table, values) in rows {
+        match tables.iter_mut().find(|(x, _)| x.name == table.name) {
+            Some((_, rows)) => rows.push(values),
+            None => tables.push((table, vec![values])),
+        }
+    }
+
+    for (table, rows) in tables {
+        let columns = table.column_list();
+        let target = if table.unique_msg_id {
+            // Kept for the life of the pooled connection, creating one per batch costs more than
+            // the COPY saves
+            let staging = format!("{}_staging", table.name);
+            transaction
+                .batch_execute(&format!(
+                    "SET LOCAL client_min_messages = warning;
+                    CREATE TEMP TABLE IF NOT EXISTS {staging} ON COMMIT DELETE ROWS AS
+                        SELECT {columns} FROM {} WITH NO DATA;",
+                    table.name
+                ))
+                .await?;
+            staging
+        } else {
+            table.name.to_string()
+        };
+
+        let sink =
+            transaction.copy_in(&format!("COPY {target} ({columns}) FROM STDIN BINARY")).await?;
+        let types: Vec<Type> = table.columns.iter().map(|(_, ty)| ty.clone()).collect();
+        let mut writer = pin!(BinaryCopyInWriter::new(sink, &types));
+
+        for values in &rows {
+            writer.as_mut().write(values).await?;
+        }
+
+        writer.finish().await?;
+
+        if table.unique_msg_id {
+            transaction
+                .batch_execute(&format!(
+                    "INSERT INTO {} ({columns}) SELECT {} FROM {target}{};",
+                    table.name,
+                    table.value_list(|_, name| name.to_string()),
+                    table.on_conflict()
+                ))
+                .await?;
+        }
+
+        debug!("Copied {} rows into {}", rows.len(), table.name);
+    }
+
+    Ok(())
+}
+
+async fn prepare(transaction: &Transaction<'_>, query: &str) -> Result<Statement, error::Error> {
+    match transaction.prepare(query).await {
+        Ok(statement) => {
+            debug!("Postgres statement prepared successfully");
+            Ok(statement)
+        }
+        Err(e) => {
+            warn!("Error preparing Postgres statement: {e}");
+            Err(error::Error::Postgres(e))
+        }
+    }
+}
+
+async fn execute(
+    transaction: &Transaction<'_>,
+    statement: &Statement,
+    params: &[&(dyn ToSql + Sync)],
+) -> Result<(), error::Error> {
+    let result =
+        tokio::time::timeout(STATEMENT_TIMEOUT, transaction.execute(statement, params)).await;
+
+    match result {
+        Ok(Ok(_)) => debug!("Postgres statement executed successfully"),
+        Ok(Err(e)) => {
+            warn!("Error executing Postgres statement: {e}");
+            return Err(error::Error::Postgres(e));
+        }
+        Err(e) => {
+            warn!("Timeout occurred while executing Postgres statement: {e}");
+            return Err(error::Error::StatementTimeout);
+        }
+    };
+
+    Ok(())
+}
+
+// Errors that may go away if the same batch is written again later, as opposed to rows that
+// Postgres will never accept. A full disk or a read-only replica after a failover clears up once
+// someone fixes the server.
+pub fn is_transient(err: &error::Error) -> bool {
+    match err {
+        error::Error::bb8(bb8::RunError::TimedOut) | error::Error::StatementTimeout => true,
+        error::Error::bb8(bb8::RunError::User(e)) | error::Error::Postgres(e) => match e.code() {
+            Some(code) => {
+                ["08", "53", "58"].iter().any(|class| code.code().starts_with(class))
+                    || [
+                        SqlState::T_R_SERIALIZATION_FAILURE,
+                        SqlState::T_R_DEADLOCK_DETECTED,
+                        SqlState::LOCK_NOT_AVAILABLE,
+                        SqlState::QUERY_CANCELED,
+                        SqlState::ADMIN_SHUTDOWN,
+                        SqlState::CRASH_SHUTDOWN,
+                        SqlState::CANNOT_CONNECT_NOW,
+                        SqlState::TOO_MANY_CONNECTIONS,
+                        SqlState::READ_ONLY_SQL_TRANSACTION,
+                    ]
+                    .contains(code)
+            }
+            // Without a SQLSTATE the server never saw the row, so only connection errors are retried
+            None => {
+                e.is_closed()
+                    || std::error::Error::source(e)
+                        .is_some_and(|source| source.is::<std::io::Error>())
+            }
+        },
+        _ => false,
+    }
+}
+
+// Errors caused by the values of some row, e.g. an invalid character or a violated constraint.
+// Only these are worth splitting a batch over, anything else affects every row alike.
+pub fn is_data_error(err: &error::Error) -> bool {
+    match err {
+        error::Error::bb8(bb8::RunError::User(e)) | error::Error::Postgres(e) => match e.code() {
+            Some(code) => ["22", "23"].iter().any(|class| code.code().starts_with(class)),
+            // Values that could not be converted before they were sent
+            None => !is_transient(err),
+        },
+        _ => false,
+    }
+}
@@ -3,21 +3,33 @@ use std::fmt;
 #[allow(non_camel_case_types)]
 #[derive(Debug)]
 pub enum Error {
+    Auth(String),
     bb8(bb8::RunError<tokio_postgres::Error>),
+    Config(String),
     Io(std::io::Error),
     Json(serde_json::Error),
     Postgres(tokio_postgres::Error),
-    Regex(regex::Error),
+    ShardFailed(u32),
+    StatementTimeout,
+    Tls(native_tls::Error),
+    Websocket(Box<tokio_tungstenite::tungstenite::Error>),
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
+            Self::Auth(ref err) => write!(f, "Authentication failed: {err}"),
             Self::bb8(ref err) => write!(f, "{err}"),
+            Self::Config(ref err) => write!(f, "Invalid config: {err}"),
             Self::Io(ref err) => write!(f, "{err}"),
             Self::Json(ref err) => write!(f, "{err}"),
             Self::Postgres(ref err) => write!(f, "{err}"),
-            Self::Regex(ref err) => write!(f, "{err}"),
+            Self::ShardFailed(thread_id) => {
+                write!(f, "Thread #{thread_id} gave up reconnecting to websocket server")
+            }
+            Self::StatementTimeout => write!(f, "Postgres statement timed out"),
+            Self::Tls(ref err) => write!(f, "{err}"),
+            Self::Websocket(ref err) => write!(f, "{err}"),
         }
     }
 }
@@ -46,8 +58,14 @@ impl From<tokio_postgres::Error> for Error {
     }
 }
 
-impl From<regex::Error> for Error {
-    fn from(err: regex::Error) -> Self {
-        Self::Regex(err)
+impl From<native_tls::Error> for Error {
+    fn from(err: native_tls::Error) -> Self {
+        Self::Tls(err)
+    }
+}
+
+impl From<tokio_tungstenite::tungstenite::Error> for Error {
+    fn from(err: tokio_tungstenite::tungstenite::Error) -> Self {
+        Self::Websocket(Box::new(err))
     }
 }
@@ -1,13 +1,40 @@
-use super::{msg::Msg, tags::Tag};
+use super::{
+    irc::{Command, IrcMessage},
+    membership::Membership,
+    moderation::{ClearChat, ClearMsg},
+    msg::Msg,
+    notice::Notice,
+    roomstate::RoomState,
+    tags::Tag,
+    usernotice::UserNotice,
+};
 
-#[derive(Debug, Clone)]
-pub struct Event {
-    pub msg: Msg,
-    pub tags: Tag,
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Event {
+    ClearChat(ClearChat),
+    ClearMsg(ClearMsg),
+    Membership(Membership),
+    Notice(Notice),
+    Privmsg { msg: Msg, tags: Box<Tag> },
+    RoomState(RoomState),
+    UserNotice(Box<UserNotice>),
 }
 
 impl Event {
-    pub const fn new(msg: Msg, tags: Tag) -> Self {
-        Self { msg, tags }
+    // Returns None for commands that are not logged
+    pub fn from_irc(message: &IrcMessage) -> Option<Self> {
+        match message.command {
+            Command::ClearChat => Some(Self::ClearChat(ClearChat::from_irc(message))),
+            Command::ClearMsg => Some(Self::ClearMsg(ClearMsg::from_irc(message))),
+            Command::Join | Command::Part => Some(Self::Membership(Membership::from_irc(message))),
+            Command::Notice => Some(Self::Notice(Notice::from_irc(message))),
+            Command::Privmsg => Some(Self::Privmsg {
+                msg: Msg::from_irc(message),
+                tags: Box::new(Tag::from_irc(message)),
+            }),
+            Command::RoomState => Some(Self::RoomState(RoomState::from_irc(message))),
+            Command::UserNotice => Some(Self::UserNotice(Box::new(UserNotice::from_irc(message)))),
+            _ => None,
+        }
     }
 }
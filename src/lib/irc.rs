@@ -0,0 +1,452 @@
+use std::{collections::HashMap, fmt};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    ClearChat,
+    ClearMsg,
+    GlobalUserState,
+    HostTarget,
+    Join,
+    Notice,
+    Numeric(u16),
+    Other(String),
+    Part,
+    Ping,
+    Pong,
+    Privmsg,
+    Reconnect,
+    RoomState,
+    UserNotice,
+    UserState,
+    Whisper,
+}
+
+impl Command {
+    pub fn parse(command: &str) -> Self {
+        match command {
+            "CLEARCHAT" => Self::ClearChat,
+            "CLEARMSG" => Self::ClearMsg,
+            "GLOBALUSERSTATE" => Self::GlobalUserState,
+            "HOSTTARGET" => Self::HostTarget,
+            "JOIN" => Self::Join,
+            "NOTICE" => Self::Notice,
+            "PART" => Self::Part,
+            "PING" => Self::Ping,
+            "PONG" => Self::Pong,
+            "PRIVMSG" => Self::Privmsg,
+            "RECONNECT" => Self::Reconnect,
+            "ROOMSTATE" => Self::RoomState,
+            "USERNOTICE" => Self::UserNotice,
+            "USERSTATE" => Self::UserState,
+            "WHISPER" => Self::Whisper,
+            _ => {
+                if command.len() == 3 {
+                    if let Ok(code) = command.parse::<u16>() {
+                        return Self::Numeric(code);
+                    }
+                }
+                Self::Other(command.to_string())
+            }
+        }
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::ClearChat => write!(f, "CLEARCHAT"),
+            Self::ClearMsg => write!(f, "CLEARMSG"),
+            Self::GlobalUserState => write!(f, "GLOBALUSERSTATE"),
+            Self::HostTarget => write!(f, "HOSTTARGET"),
+            Self::Join => write!(f, "JOIN"),
+            Self::Notice => write!(f, "NOTICE"),
+            Self::Numeric(code) => write!(f, "{code:03}"),
+            Self::Other(command) => write!(f, "{command}"),
+            Self::Part => write!(f, "PART"),
+            Self::Ping => write!(f, "PING"),
+            Self::Pong => write!(f, "PONG"),
+            Self::Privmsg => write!(f, "PRIVMSG"),
+            Self::Reconnect => write!(f, "RECONNECT"),
+            Self::RoomState => write!(f, "ROOMSTATE"),
+            Self::UserNotice => write!(f, "USERNOTICE"),
+            Self::UserState => write!(f, "USERSTATE"),
+            Self::Whisper => write!(f, "WHISPER"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Prefix {
+    pub nick: String,
+    pub user: String,
+    pub host: String,
+}
+
+impl Prefix {
+    // nick!user@host, or a bare server name such as tmi.twitch.tv
+    pub fn parse(prefix: &str) -> Self {
+        let (nick, rest) = match prefix.split_once('!') {
+            Some((nick, rest)) => (nick, Some(rest)),
+            None => (prefix, None),
+        };
+
+        match rest {
+            Some(rest) => {
+                let (user, host) = rest.split_once('@').unwrap_or((rest, ""));
+
+                Self { nick: nick.to_string(), user: user.to_string(), host: host.to_string() }
+            }
+            None => match nick.split_once('@') {
+                Some((nick, host)) => {
+                    Self { nick: nick.to_string(), user: String::new(), host: host.to_string() }
+                }
+                None if nick.contains('.') => {
+                    Self { nick: String::new(), user: String::new(), host: nick.to_string() }
+                }
+                None => Self { nick: nick.to_string(), user: String::new(), host: String::new() },
+            },
+        }
+    }
+}
+
+impl fmt::Display for Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.nick.is_empty(), self.user.is_empty(), self.host.is_empty()) {
+            (true, _, _) => write!(f, "{}", self.host),
+            (false, true, true) => write!(f, "{}", self.nick),
+            (false, true, false) => write!(f, "{}@{}", self.nick, self.host),
+            (false, false, _) => write!(f, "{}!{}@{}", self.nick, self.user, self.host),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IrcMessage {
+    pub tags: HashMap<String, String>,
+    pub prefix: Option<Prefix>,
+    pub command: Command,
+    pub params: Vec<String>,
+}
+
+impl IrcMessage {
+    pub fn new(command: Command, params: Vec<String>) -> Self {
+        Self { tags: HashMap::new(), prefix: None, command, params }
+    }
+
+    // https://ircv3.net/specs/extensions/message-tags
+    // [@tags] [:prefix] <command> [params] [:trailing]
+    pub fn parse(data: &str) -> Option<Self> {
+        let mut rest = data.trim_end_matches(['\r', '\n']).trim_start_matches(' ');
+        let mut tags = HashMap::new();
+        let mut prefix = None;
+
+        if let Some(stripped) = rest.strip_prefix('@') {
+            let (raw_tags, remainder) = stripped.split_once(' ')?;
+
+            for tag in raw_tags.split(';').filter(|x| !x.is_empty()) {
+                let (tag_name, tag_value) = tag.split_once('=').unwrap_or((tag, ""));
+
+                tags.insert(tag_name.to_string(), unescape_tag_value(tag_value));
+            }
+
+            rest = remainder.trim_start_matches(' ');
+        }
+
+        if let Some(stripped) = rest.strip_prefix(':') {
+            let (raw_prefix, remainder) = stripped.split_once(' ')?;
+
+            prefix = Some(Prefix::parse(raw_prefix));
+            rest = remainder.trim_start_matches(' ');
+        }
+
+        let (command, mut rest) = rest.split_once(' ').unwrap_or((rest, ""));
+
+        if command.is_empty() {
+            return None;
+        }
+
+        let mut params = Vec::new();
+
+        loop {
+            rest = rest.trim_start_matches(' ');
+
+            if rest.is_empty() {
+                break;
+            }
+
+            if let Some(trailing) = rest.strip_prefix(':') {
+                params.push(trailing.to_string());
+                break;
+            }
+
+            let (param, remainder) = rest.split_once(' ').unwrap_or((rest, ""));
+
+            params.push(param.to_string());
+            rest = remainder;
+        }
+
+        Some(Self { tags, prefix, command: Command::parse(command), params })
+    }
+
+    pub fn tag(&self, name: &str) -> Option<&str> {
+        self.tags.get(name).map(String::as_str)
+    }
+
+    pub fn tags_raw(&self) -> String {
+        serde_json::to_string(&self.tags).unwrap_or_default()
+    }
+
+    pub fn nick(&self) -> &str {
+        self.prefix.as_ref().map_or("", |prefix| prefix.nick.as_str())
+    }
+
+    // First parameter when it names a channel, e.g. PRIVMSG #channel :text
+    pub fn channel(&self) -> &str {
+        self.params.first().filter(|x| x.starts_with('#')).map_or("", String::as_str)
+    }
+
+    // Last parameter, which is the trailing text for commands that carry one
+    pub fn trailing(&self) -> &str {
+        match self.params.as_slice() {
+            [] => "",
+            [only] if only.starts_with('#') => "",
+            [.., last] => last,
+        }
+    }
+}
+
+impl fmt::Display for IrcMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.tags.is_empty() {
+            let mut tags: Vec<(&String, &String)> = self.tags.iter().collect();
+
+            tags.sort();
+
+            let tags: Vec<String> = tags
+                .into_iter()
+                .map(|(k, v)| {
+                    if v.is_empty() {
+                        k.to_string()
+                    } else {
+                        format!("{k}={}", escape_tag_value(v))
+                    }
+                })
+                .collect();
+
+            write!(f, "@{} ", tags.join(";"))?;
+        }
+
+        if let Some(prefix) = &self.prefix {
+            write!(f, ":{prefix} ")?;
+        }
+
+        write!(f, "{}", self.command)?;
+
+        if let Some((last, middle)) = self.params.split_last() {
+            for param in middle {
+                write!(f, " {param}")?;
+            }
+
+            if last.is_empty() || last.starts_with(':') || last.contains(' ') {
+                write!(f, " :{last}")?;
+            } else {
+                write!(f, " {last}")?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// A single websocket frame may carry several \r\n terminated IRC lines
+pub fn split_frame(data: &str) -> impl Iterator<Item = &str> {
+    data.split('\n').map(|line| line.trim_end_matches('\r')).filter(|line| !line.is_empty())
+}
+
+// https://ircv3.net/specs/extensions/message-tags#escaping-values
+pub fn unescape_tag_value(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some(':') => unescaped.push(';'),
+                Some('s') => unescaped.push(' '),
+                Some('\\') => unescaped.push('\\'),
+                Some('r') => unescaped.push('\r'),
+                Some('n') => unescaped.push('\n'),
+                Some(other) => unescaped.push(other),
+                // A trailing lone backslash is dropped
+                None => {}
+            }
+        } else {
+            unescaped.push(c);
+        }
+    }
+
+    unescaped
+}
+
+pub fn escape_tag_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        match c {
+            ';' => escaped.push_str(r"\:"),
+            ' ' => escaped.push_str(r"\s"),
+            '\\' => escaped.push_str(r"\\"),
+            '\r' => escaped.push_str(r"\r"),
+            '\n' => escaped.push_str(r"\n"),
+            _ => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_privmsg_with_tags() {
+        let message = IrcMessage::parse(
+            "@badge-info=;badges=broadcaster/1;color=#FF0000;display-name=Ronni;emotes=;\
+             id=b34ccfc7-4977-403a-8a94-33c6bac34fb8;mod=0;room-id=1337;subscriber=0;\
+             tmi-sent-ts=1507246572675;turbo=1;user-id=1337;user-type=global_mod \
+             :ronni!ronni@ronni.tmi.twitch.tv PRIVMSG #ronni :Kappa Keepo Kappa\r\n",
+        )
+        .unwrap();
+
+        assert_eq!(message.command, Command::Privmsg);
+        assert_eq!(message.nick(), "ronni");
+        assert_eq!(message.channel(), "#ronni");
+        assert_eq!(message.trailing(), "Kappa Keepo Kappa");
+        assert_eq!(message.tag("id"), Some("b34ccfc7-4977-403a-8a94-33c6bac34fb8"));
+        assert_eq!(message.tag("badge-info"), Some(""));
+        assert_eq!(message.tag("missing"), None);
+    }
+
+    #[test]
+    fn parses_tags_without_a_value() {
+        let message =
+            IrcMessage::parse("@emote-only;slow=10;; :tmi.twitch.tv ROOMSTATE #dallas").unwrap();
+
+        assert_eq!(message.tag("emote-only"), Some(""));
+        assert_eq!(message.tag("slow"), Some("10"));
+        assert_eq!(message.tags.len(), 2);
+    }
+
+    #[test]
+    fn parses_bare_server_prefix() {
+        let message = IrcMessage::parse(":tmi.twitch.tv 001 justinfan123 :Welcome, GLHF!").unwrap();
+        let prefix = message.prefix.as_ref().unwrap();
+
+        assert_eq!(message.command, Command::Numeric(1));
+        assert_eq!(prefix.host, "tmi.twitch.tv");
+        assert_eq!(message.nick(), "");
+        assert_eq!(message.channel(), "");
+        assert_eq!(message.trailing(), "Welcome, GLHF!");
+    }
+
+    #[test]
+    fn parses_prefix_forms() {
+        assert_eq!(
+            Prefix::parse("foo!bar@foo.tmi.twitch.tv"),
+            Prefix {
+                nick: "foo".to_string(),
+                user: "bar".to_string(),
+                host: "foo.tmi.twitch.tv".to_string()
+            }
+        );
+        assert_eq!(
+            Prefix::parse("foo@foo.tmi.twitch.tv"),
+            Prefix {
+                nick: "foo".to_string(),
+                user: String::new(),
+                host: "foo.tmi.twitch.tv".to_string()
+            }
+        );
+        assert_eq!(
+            Prefix::parse("foo"),
+            Prefix { nick: "foo".to_string(), user: String::new(), host: String::new() }
+        );
+    }
+
+    #[test]
+    fn single_channel_param_has_no_trailing() {
+        let part = IrcMessage::parse(":foo!foo@foo.tmi.twitch.tv PART #bar").unwrap();
+        let clear = IrcMessage::parse("@room-id=1 :tmi.twitch.tv CLEARCHAT #bar :ronni").unwrap();
+
+        assert_eq!(part.command, Command::Part);
+        assert_eq!(part.channel(), "#bar");
+        assert_eq!(part.trailing(), "");
+        assert_eq!(clear.channel(), "#bar");
+        assert_eq!(clear.trailing(), "ronni");
+    }
+
+    #[test]
+    fn parses_ping_and_unknown_commands() {
+        let ping = IrcMessage::parse("PING :tmi.twitch.tv").unwrap();
+        let other = IrcMessage::parse(":tmi.twitch.tv CAP * ACK :twitch.tv/tags").unwrap();
+
+        assert_eq!(ping.command, Command::Ping);
+        assert!(ping.prefix.is_none());
+        assert_eq!(ping.trailing(), "tmi.twitch.tv");
+        assert_eq!(other.command, Command::Other("CAP".to_string()));
+        assert_eq!(other.params, ["*", "ACK", "twitch.tv/tags"]);
+    }
+
+    #[test]
+    fn rejects_incomplete_lines() {
+        assert_eq!(IrcMessage::parse(""), None);
+        assert_eq!(IrcMessage::parse("@id=1"), None);
+        assert_eq!(IrcMessage::parse(":tmi.twitch.tv"), None);
+        assert_eq!(IrcMessage::parse("@id=1 :tmi.twitch.tv "), None);
+    }
+
+    #[test]
+    fn display_round_trips() {
+        let line = "@badges;id=1 :foo!foo@foo.tmi.twitch.tv PRIVMSG #bar :hello there";
+        let message = IrcMessage::parse(line).unwrap();
+
+        assert_eq!(message.to_string(), line);
+        assert_eq!(IrcMessage::parse(&message.to_string()), Some(message));
+    }
+
+    #[test]
+    fn unescapes_tag_values() {
+        assert_eq!(unescape_tag_value(r"hello\sworld\:\\\r\n"), "hello world;\\\r\n");
+        assert_eq!(unescape_tag_value(r"\b"), "b");
+        assert_eq!(unescape_tag_value("trailing\\"), "trailing");
+        assert_eq!(unescape_tag_value(""), "");
+    }
+
+    #[test]
+    fn escapes_tag_values() {
+        let value = "a; b\\c\r\n";
+
+        assert_eq!(escape_tag_value(value), r"a\:\sb\\c\r\n");
+        assert_eq!(unescape_tag_value(&escape_tag_value(value)), value);
+    }
+
+    #[test]
+    fn unescapes_tags_while_parsing() {
+        let message = IrcMessage::parse(
+            r"@system-msg=5\sraiders\sfrom\sfoo;msg-param-x=a\:b\ :tmi.twitch.tv USERNOTICE #bar",
+        )
+        .unwrap();
+
+        assert_eq!(message.tag("system-msg"), Some("5 raiders from foo"));
+        assert_eq!(message.tag("msg-param-x"), Some("a;b"));
+    }
+
+    #[test]
+    fn splits_frames_into_lines() {
+        let lines: Vec<&str> =
+            split_frame("PING :tmi.twitch.tv\r\n:a!a@a.tmi.twitch.tv JOIN #b\r\n\r\n").collect();
+
+        assert_eq!(lines, ["PING :tmi.twitch.tv", ":a!a@a.tmi.twitch.tv JOIN #b"]);
+    }
+}
@@ -0,0 +1,90 @@
+use std::{collections::HashMap, time::Duration};
+use tokio::time::Instant;
+
+#[derive(Debug, Clone)]
+pub enum JoinStatus {
+    Pending(Instant),
+    Joined,
+    Failed { reason: String, since: Instant },
+}
+
+// Tracks whether each channel on a connection was actually joined, since sending JOIN only
+// means Twitch received the request
+#[derive(Debug, Default)]
+pub struct JoinTracker {
+    statuses: HashMap<String, JoinStatus>,
+}
+
+impl JoinTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn pending(&mut self, channel: &str) {
+        self.statuses.insert(channel.to_string(), JoinStatus::Pending(Instant::now()));
+    }
+
+    // Returns true if the channel was not already marked as joined
+    pub fn joined(&mut self, channel: &str) -> bool {
+        !matches!(
+            self.statuses.insert(channel.to_string(), JoinStatus::Joined),
+            Some(JoinStatus::Joined)
+        )
+    }
+
+    pub fn failed(&mut self, channel: &str, reason: &str) {
+        self.statuses.insert(
+            channel.to_string(),
+            JoinStatus::Failed { reason: reason.to_string(), since: Instant::now() },
+        );
+    }
+
+    pub fn remove(&mut self, channel: &str) {
+        self.statuses.remove(channel);
+    }
+
+    pub fn has_pending(&self) -> bool {
+        self.statuses.values().any(|status| matches!(status, JoinStatus::Pending(_)))
+    }
+
+    // Channels that were neither confirmed nor refused within the timeout are marked as failed
+    pub fn expire_pending(&mut self, timeout: Duration) -> Vec<String> {
+        let mut expired = Vec::new();
+
+        for (channel, status) in &mut self.statuses {
+            if let JoinStatus::Pending(since) = status {
+                if since.elapsed() >= timeout {
+                    *status = JoinStatus::Failed {
+                        reason: "no response".to_string(),
+                        since: Instant::now(),
+                    };
+                    expired.push(channel.clone());
+                }
+            }
+        }
+
+        expired
+    }
+
+    pub fn retry_due(&self, retry_after: Duration) -> Vec<String> {
+        self.statuses
+            .iter()
+            .filter_map(|(channel, status)| match status {
+                JoinStatus::Failed { since, .. } if since.elapsed() >= retry_after => {
+                    Some(channel.clone())
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn failures(&self) -> Vec<(&str, &str)> {
+        self.statuses
+            .iter()
+            .filter_map(|(channel, status)| match status {
+                JoinStatus::Failed { reason, .. } => Some((channel.as_str(), reason.as_str())),
+                _ => None,
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,743 @@
+use futures_util::{SinkExt, StreamExt};
+use log::{debug, info, warn};
+use std::{
+    cmp,
+    collections::{HashMap, VecDeque},
+    fs, io,
+    sync::Arc,
+    time,
+};
+use tokio::{net::TcpStream, sync::oneshot, time::Instant};
+use tokio_tungstenite::{
+    connect_async_tls_with_config,
+    tungstenite::{self, Message},
+    Connector, MaybeTlsStream, WebSocketStream,
+};
+
+use super::{config, error, event, irc, joins, ratelimit, roomstate, writer};
+
+pub type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+const JOIN_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+const JOIN_RETRY: time::Duration = time::Duration::from_secs(300);
+const CONNECT_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+const LOGIN_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+const DRAIN_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+// ws:// and wss:// are both accepted, wss:// trusts the system roots plus the optional ca_file
+pub async fn connect(config: &config::Config) -> Result<Socket, error::Error> {
+    let connector = match &config.ca_file {
+        Some(ca_file) => {
+            let mut builder = native_tls::TlsConnector::builder();
+
+            for certificate in native_tls::Certificate::stack_from_pem(&fs::read(ca_file)?)? {
+                builder.add_root_certificate(certificate);
+            }
+
+            Some(Connector::NativeTls(builder.build()?))
+        }
+        None => None,
+    };
+    // A server that accepts the connection but stalls the handshake would otherwise hang here
+    let handshake = connect_async_tls_with_config(&config.server, None, false, connector);
+    let (socket, _response) = match tokio::time::timeout(CONNECT_TIMEOUT, handshake).await {
+        Ok(result) => result?,
+        Err(_) => {
+            return Err(error::Error::Io(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Timed out connecting to websocket server",
+            )));
+        }
+    };
+
+    Ok(socket)
+}
+
+async fn send_line(
+    limiter: &ratelimit::RateLimiter,
+    socket: &mut Socket,
+    line: String,
+) -> Result<(), error::Error> {
+    limiter.commands.acquire().await;
+    socket.send(Message::Text(line)).await?;
+
+    Ok(())
+}
+
+async fn send_join(
+    limiter: &ratelimit::RateLimiter,
+    socket: &mut Socket,
+    channel: &str,
+) -> Result<(), error::Error> {
+    limiter.joins.acquire().await;
+    socket.send(Message::Text(format!("JOIN {channel}"))).await?;
+
+    Ok(())
+}
+
+async fn pong(
+    limiter: &ratelimit::RateLimiter,
+    socket: &mut Socket,
+    ping: &irc::IrcMessage,
+) -> Result<(), error::Error> {
+    let line = irc::IrcMessage::new(irc::Command::Pong, vec![ping.trailing().to_string()]);
+
+    send_line(limiter, socket, line.to_string()).await
+}
+
+// Logging in needs nothing from the listener but the account, so a replacement connection can
+// log in while the listener keeps reading the old one
+async fn authenticate(
+    socket: &mut Socket,
+    config: &config::Config,
+    account: &config::Account,
+    limiter: &ratelimit::RateLimiter,
+) -> Result<(), error::Error> {
+    send_line(
+        limiter,
+        socket,
+        "CAP REQ :twitch.tv/membership twitch.tv/tags twitch.tv/commands".into(),
+    )
+    .await?;
+    if !config.anonymous {
+        send_line(limiter, socket, format!("PASS {}", account.oauth)).await?;
+    }
+    send_line(limiter, socket, format!("NICK {}", account.nickname)).await?;
+
+    match tokio::time::timeout(LOGIN_TIMEOUT, wait_for_login(socket, limiter)).await {
+        Ok(result) => result,
+        Err(_) => Err(error::Error::Io(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "Timed out waiting for login response",
+        ))),
+    }
+}
+
+async fn wait_for_login(
+    socket: &mut Socket,
+    limiter: &ratelimit::RateLimiter,
+) -> Result<(), error::Error> {
+    while let Some(data) = socket.next().await {
+        let Message::Text(data) = data? else {
+            continue;
+        };
+
+        for line in irc::split_frame(&data) {
+            let Some(message) = irc::IrcMessage::parse(line) else {
+                continue;
+            };
+
+            match message.command {
+                irc::Command::Numeric(1) | irc::Command::GlobalUserState => return Ok(()),
+                irc::Command::Notice => {
+                    return Err(error::Error::Auth(message.trailing().to_string()));
+                }
+                irc::Command::Ping => pong(limiter, socket, &message).await?,
+                _ => {}
+            }
+        }
+    }
+
+    Err(error::Error::Websocket(Box::new(tungstenite::Error::ConnectionClosed)))
+}
+
+// Connects, logs in and sends JOIN for every channel on a second connection. Returns the
+// connection and the channels whose JOIN was sent.
+async fn open_replacement(
+    thread_id: u32,
+    config: config::Config,
+    account: config::Account,
+    limiter: Arc<ratelimit::RateLimiter>,
+    channels: Vec<String>,
+) -> Result<(Socket, Vec<String>), error::Error> {
+    let mut socket = connect(&config).await?;
+
+    info!("Thread #{thread_id}: Opened replacement connection");
+
+    authenticate(&mut socket, &config, &account, &limiter).await?;
+
+    info!("Thread #{thread_id}: Logged in as {} on replacement connection", account.nickname);
+
+    let mut sent = Vec::new();
+
+    for channel in channels {
+        match send_join(&limiter, &mut socket, &channel).await {
+            Ok(()) => {
+                debug!("Thread #{thread_id}: Sent JOIN for {channel}");
+                sent.push(channel);
+            }
+            Err(e) => warn!("Thread #{thread_id}: Error joining {channel}: {e}"),
+        }
+    }
+
+    Ok((socket, sent))
+}
+
+// Channel changes sent to a running thread after the config was reloaded. A JOIN can carry a
+// sender that fires once Twitch confirms it.
+#[derive(Debug)]
+pub enum Control {
+    Join(String, Option<oneshot::Sender<()>>),
+    Part(String),
+}
+
+// Per-connection state that has to survive reconnects: the pending batch, join status and the
+// last known room state of every channel
+pub struct Listener {
+    pub thread_id: u32,
+    pub config: config::Config,
+    pub account: config::Account,
+    pub channels: Vec<String>,
+    batch: Vec<event::Event>,
+    batch_size: usize,
+    buffer: VecDeque<Vec<event::Event>>,
+    frame_count: u64,
+    line_count: usize,
+    // Keyed by channel, so parting a channel forgets its room state
+    room_states: HashMap<String, roomstate::RoomState>,
+    joins: joins::JoinTracker,
+    join_waiters: HashMap<String, oneshot::Sender<()>>,
+    last_read: Instant,
+    last_ping: Instant,
+    pending_ping: Option<(String, Instant)>,
+    ping_count: u64,
+    latency: Option<time::Duration>,
+    limiter: Arc<ratelimit::RateLimiter>,
+    writer: writer::Writer,
+    tx: writer::BatchSender,
+}
+
+impl Listener {
+    pub fn new(
+        thread_id: u32,
+        config: config::Config,
+        account: config::Account,
+        channels: Vec<String>,
+        limiter: Arc<ratelimit::RateLimiter>,
+        writer: writer::Writer,
+        tx: writer::BatchSender,
+    ) -> Self {
+        let batch_size = config.backpressure.min_batch_size;
+
+        Self {
+            thread_id,
+            config,
+            account,
+            channels,
+            batch: Vec::new(),
+            batch_size,
+            buffer: VecDeque::new(),
+            frame_count: 0,
+            line_count: 0,
+            room_states: HashMap::new(),
+            joins: joins::JoinTracker::new(),
+            join_waiters: HashMap::new(),
+            last_read: Instant::now(),
+            last_ping: Instant::now(),
+            pending_ping: None,
+            ping_count: 0,
+            latency: None,
+            limiter,
+            writer,
+            tx,
+        }
+    }
+
+    pub async fn send(&self, socket: &mut Socket, line: String) -> Result<(), error::Error> {
+        send_line(&self.limiter, socket, line).await
+    }
+
+    async fn join(&mut self, socket: &mut Socket, channel: &str) -> Result<(), error::Error> {
+        send_join(&self.limiter, socket, channel).await?;
+        self.joins.pending(channel);
+
+        Ok(())
+    }
+
+    pub async fn login(&mut self, socket: &mut Socket) -> Result<(), error::Error> {
+        let thread_id = self.thread_id;
+
+        authenticate(socket, &self.config, &self.account, &self.limiter).await?;
+
+        info!("Thread #{thread_id}: Logged in as {}", self.account.nickname);
+
+        self.reset_keepalive();
+
+        Ok(())
+    }
+
+    pub async fn join_all(&mut self, socket: &mut Socket) {
+        let thread_id = self.thread_id;
+
+        info!("Thread #{thread_id}: {:?}", self.channels);
+
+        for channel in self.channels.clone() {
+            match self.join(socket, &channel).await {
+                Ok(()) => debug!("Thread #{thread_id}: Sent JOIN for {channel}"),
+                Err(e) => warn!("Thread #{thread_id}: Error joining {channel}: {e}"),
+            }
+        }
+    }
+
+    // Updates the channel list without touching the socket, returns false if nothing changed.
+    // Used while disconnected so the next login joins the right channels.
+    pub fn update_channels(&mut self, control: &mut Control) -> bool {
+        match control {
+            Control::Join(channel, waiter) => {
+                if let Some(waiter) = waiter.take() {
+                    self.join_waiters.insert(channel.clone(), waiter);
+                }
+                if self.channels.contains(channel) {
+                    return false;
+                }
+                self.channels.push(channel.clone());
+            }
+            Control::Part(channel) => {
+                if !self.channels.contains(channel) {
+                    return false;
+                }
+                self.channels.retain(|x| x != channel);
+                self.joins.remove(channel);
+                self.join_waiters.remove(channel);
+                self.room_states.remove(channel);
+            }
+        }
+
+        true
+    }
+
+    pub async fn control(
+        &mut self,
+        socket: &mut Socket,
+        mut control: Control,
+    ) -> Result<(), error::Error> {
+        let thread_id = self.thread_id;
+
+        if !self.update_channels(&mut control) {
+            return Ok(());
+        }
+
+        match control {
+            Control::Join(channel, _) => {
+                self.join(socket, &channel).await?;
+                info!("Thread #{thread_id}: Sent JOIN for {channel}");
+            }
+            Control::Part(channel) => {
+                self.send(socket, format!("PART {channel}")).await?;
+                info!("Thread #{thread_id}: Parted {channel}");
+            }
+        }
+
+        Ok(())
+    }
+
+    // Twitch answers a successful login with 001 and GLOBALUSERSTATE, and a bad or expired token
+    // with a NOTICE before closing the connection
+    // Twitch sends RECONNECT ahead of a server restart. Open and join a second connection first
+    // while still reading the old one, then close the old one and log whatever it still had
+    // buffered, so there is no gap. If the second connection fails the old one stays in use
+    // until its server hangs up.
+    pub async fn handover(&mut self, socket: &mut Socket) -> Result<(), error::Error> {
+        let thread_id = self.thread_id;
+        let replacement = open_replacement(
+            thread_id,
+            self.config.clone(),
+            self.account.clone(),
+            Arc::clone(&self.limiter),
+            self.channels.clone(),
+        );
+        let mut old_open = true;
+
+        tokio::pin!(replacement);
+
+        let (mut new_socket, sent) = loop {
+            tokio::select! {
+                result = &mut replacement => match result {
+                    Ok(replacement) => break replacement,
+                    Err(e) if old_open => {
+                        warn!(
+                            "Thread #{thread_id}: Error opening replacement connection, \
+                             staying on the old one: {e}"
+                        );
+                        return Ok(());
+                    }
+                    Err(e) => return Err(e),
+                },
+                data = socket.next(), if old_open => match data {
+                    Some(Ok(Message::Text(data))) => {
+                        self.handle_frame(socket, &data).await;
+                    }
+                    Some(Ok(_)) => {}
+                    // Nothing left to fall back on, so the replacement has to work
+                    Some(Err(_)) | None => old_open = false,
+                },
+            }
+        };
+
+        self.reset_keepalive();
+
+        for channel in &sent {
+            self.joins.pending(channel);
+        }
+
+        let deadline = tokio::time::sleep(JOIN_TIMEOUT);
+
+        tokio::pin!(deadline);
+
+        while self.joins.has_pending() {
+            tokio::select! {
+                data = new_socket.next() => match data {
+                    Some(Ok(Message::Text(data))) => {
+                        self.handle_frame(&mut new_socket, &data).await;
+                    }
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) if !old_open => return Err(e.into()),
+                    None if !old_open => return Err(tungstenite::Error::ConnectionClosed.into()),
+                    Some(Err(_)) | None => {
+                        warn!(
+                            "Thread #{thread_id}: Replacement connection closed while joining, \
+                             staying on the old one"
+                        );
+                        return Ok(());
+                    }
+                },
+                data = socket.next(), if old_open => match data {
+                    Some(Ok(Message::Text(data))) => {
+                        self.handle_frame(socket, &data).await;
+                    }
+                    Some(Ok(_)) => {}
+                    // The old server may hang up first, which is expected
+                    Some(Err(_)) | None => break,
+                },
+                () = &mut deadline => break,
+            }
+        }
+
+        if let Err(e) = socket.close(None).await {
+            debug!("Thread #{thread_id}: Error closing old connection: {e}");
+        }
+
+        let drain = async {
+            while let Some(Ok(data)) = socket.next().await {
+                if let Message::Text(data) = data {
+                    self.handle_frame(&mut new_socket, &data).await;
+                }
+            }
+        };
+
+        if tokio::time::timeout(DRAIN_TIMEOUT, drain).await.is_err() {
+            debug!("Thread #{thread_id}: Timed out draining old connection");
+        }
+
+        *socket = new_socket;
+
+        info!("Thread #{thread_id}: Switched over to replacement connection");
+
+        Ok(())
+    }
+
+    pub fn reset_keepalive(&mut self) {
+        self.last_read = Instant::now();
+        self.last_ping = Instant::now();
+        self.pending_ping = None;
+    }
+
+    // Sends our own PING on an interval and fails the connection if the PONG is late or nothing
+    // at all has been read for too long, which catches half-open TCP connections
+    pub async fn keepalive(&mut self, socket: &mut Socket) -> Result<(), error::Error> {
+        let read_timeout = time::Duration::from_secs(self.config.read_timeout_secs);
+        let pong_timeout = time::Duration::from_secs(self.config.pong_timeout_secs);
+        let ping_interval = time::Duration::from_secs(self.config.ping_interval_secs);
+
+        if self.last_read.elapsed() >= read_timeout {
+            return Err(error::Error::Io(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("No data received in {} seconds", read_timeout.as_secs()),
+            )));
+        }
+
+        match &self.pending_ping {
+            Some((_, sent)) if sent.elapsed() >= pong_timeout => {
+                return Err(error::Error::Io(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("No PONG received in {} seconds", pong_timeout.as_secs()),
+                )));
+            }
+            None if self.last_ping.elapsed() >= ping_interval => {
+                self.ping_count += 1;
+
+                let payload = format!("keepalive-{}", self.ping_count);
+
+                self.send(
+                    socket,
+                    irc::IrcMessage::new(irc::Command::Ping, vec![payload.clone()]).to_string(),
+                )
+                .await?;
+                self.last_ping = Instant::now();
+                self.pending_ping = Some((payload, Instant::now()));
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn handle_pong(&mut self, pong: &irc::IrcMessage) {
+        if let Some((payload, sent)) = &self.pending_ping {
+            if pong.trailing() == payload {
+                let latency = sent.elapsed();
+
+                debug!("Thread #{}: PONG received in {} ms", self.thread_id, latency.as_millis());
+
+                self.latency = Some(latency);
+                self.pending_ping = None;
+            }
+        }
+    }
+
+    pub async fn check_joins(&mut self, socket: &mut Socket) {
+        let thread_id = self.thread_id;
+
+        for channel in self.joins.expire_pending(JOIN_TIMEOUT) {
+            warn!("Thread #{thread_id}: No response to JOIN for {channel}");
+        }
+
+        let retry_channels = self.joins.retry_due(JOIN_RETRY);
+
+        if !retry_channels.is_empty() {
+            warn!("Thread #{thread_id}: Failed channels: {:?}", self.joins.failures());
+
+            for channel in retry_channels {
+                info!("Thread #{thread_id}: Retrying JOIN for {channel}");
+
+                if let Err(e) = self.join(socket, &channel).await {
+                    warn!("Thread #{thread_id}: Error joining {channel}: {e}");
+                }
+            }
+        }
+    }
+
+    // Returns true if the server asked us to reconnect. Replies to PING go out on `socket`.
+    pub async fn handle_frame(&mut self, socket: &mut Socket, data: &str) -> bool {
+        let thread_id = self.thread_id;
+        let lines: Vec<&str> = irc::split_frame(data).collect();
+        let mut reconnect = false;
+
+        self.frame_count += 1;
+        self.line_count += lines.len();
+        self.last_read = Instant::now();
+
+        debug!(
+            "Thread #{thread_id}: Frame #{} carried {} lines ({} total)",
+            self.frame_count,
+            lines.len(),
+            self.line_count
+        );
+
+        for line in lines {
+            let Some(message) = irc::IrcMessage::parse(line) else {
+                continue;
+            };
+
+            match message.command {
+                irc::Command::Ping => {
+                    if let Err(e) = pong(&self.limiter, socket, &message).await {
+                        warn!("Thread #{thread_id}: Error sending PONG: {e}");
+                    }
+                }
+                irc::Command::Pong => self.handle_pong(&message),
+                irc::Command::Reconnect => {
+                    info!("Thread #{thread_id}: Server requested a reconnect");
+                    reconnect = true;
+                }
+                _ => {}
+            }
+
+            if let Some(event) = event::Event::from_irc(&message) {
+                if let Some(event) = self.handle_event(event) {
+                    self.push(event).await;
+                }
+            }
+        }
+
+        self.flush_buffer();
+
+        reconnect
+    }
+
+    // Returns true if the channel was not already marked as joined, and tells whoever waits for
+    // the JOIN
+    fn joined(&mut self, channel: &str) -> bool {
+        if let Some(waiter) = self.join_waiters.remove(channel) {
+            // The waiter may have given up already
+            let _ = waiter.send(());
+        }
+
+        self.joins.joined(channel)
+    }
+
+    // Updates join and room state, returns the event if it should be logged
+    fn handle_event(&mut self, mut event: event::Event) -> Option<event::Event> {
+        let thread_id = self.thread_id;
+
+        match &mut event {
+            event::Event::Membership(membership)
+                if membership.login.eq_ignore_ascii_case(&self.account.nickname) =>
+            {
+                membership.is_self = true;
+
+                if membership.command == "JOIN" && self.joined(&membership.channel) {
+                    info!("Thread #{thread_id}: Joined {} successfully", membership.channel);
+                }
+            }
+            event::Event::Notice(notice) if notice.is_join_failure() => {
+                self.joins.failed(&notice.channel, &notice.notice_type);
+
+                warn!(
+                    "Thread #{thread_id}: Failed to join {}: {} ({}), retrying in {} seconds",
+                    notice.channel,
+                    notice.content,
+                    notice.notice_type,
+                    JOIN_RETRY.as_secs()
+                );
+            }
+            event::Event::RoomState(state) => {
+                if self.joined(&state.channel) {
+                    info!("Thread #{thread_id}: Joined {} successfully", state.channel);
+                }
+
+                if let Some(previous) = self.room_states.get(&state.channel) {
+                    state.merge(previous);
+
+                    if state.same_modes(previous) {
+                        return None;
+                    }
+                }
+
+                self.room_states.insert(state.channel.clone(), state.clone());
+            }
+            _ => {}
+        }
+
+        if matches!(event, event::Event::Membership(_)) && !self.config.log_membership {
+            return None;
+        }
+
+        Some(event)
+    }
+
+    async fn push(&mut self, event: event::Event) {
+        let backpressure = &self.config.backpressure;
+
+        self.writer.counters.received(1);
+        self.batch.push(event);
+
+        if self.batch.len() >= self.batch_size {
+            let batch_ready = self.batch.split_off(0);
+
+            if self.tx.try_send(batch_ready.clone()).is_err() {
+                self.buffer.push_back(batch_ready);
+                self.batch_size = cmp::min(self.batch_size + 10, backpressure.max_batch_size);
+                self.limit_buffer().await;
+            } else {
+                self.batch_size =
+                    cmp::max(self.batch_size.saturating_sub(10), backpressure.min_batch_size);
+            }
+        }
+    }
+
+    fn buffered_events(&self) -> usize {
+        self.buffer.iter().map(Vec::len).sum()
+    }
+
+    // Gets the events this connection holds, in its buffer, queued and being written, back under
+    // max_buffered_events. Only buffered batches can be let go of, oldest first.
+    async fn limit_buffer(&mut self) {
+        let thread_id = self.thread_id;
+        let max_buffered_events = self.config.backpressure.max_buffered_events;
+
+        while self.buffered_events() + self.tx.queued() > max_buffered_events {
+            let Some(batch_ready) = self.buffer.pop_front() else {
+                break;
+            };
+            let count = batch_ready.len();
+
+            match self.config.backpressure.on_full {
+                config::OverflowPolicy::Block => {
+                    if self.tx.send(batch_ready).await.is_err() {
+                        warn!("Thread #{thread_id}: Writer stopped, dropping {count} events");
+                        self.writer.counters.lost(count);
+                    }
+                }
+                config::OverflowPolicy::Spill if self.writer.spill(&batch_ready).await => {}
+                config::OverflowPolicy::Spill | config::OverflowPolicy::DropOldest => {
+                    warn!("Thread #{thread_id}: Buffer is full, dropping {count} events");
+                    self.writer.counters.dropped(count);
+                }
+            }
+        }
+    }
+
+    fn flush_buffer(&mut self) {
+        while let Some(batch_ready) = self.buffer.pop_front() {
+            if self.tx.try_send(batch_ready.clone()).is_err() {
+                self.buffer.push_front(batch_ready);
+                break;
+            }
+        }
+    }
+
+    // Waits for the writer to take the pending batch and everything buffered, used on shutdown
+    pub async fn flush(&mut self) {
+        if !self.batch.is_empty() {
+            let batch_ready = self.batch.split_off(0);
+            self.buffer.push_back(batch_ready);
+        }
+
+        while let Some(batch_ready) = self.buffer.pop_front() {
+            let count = batch_ready.len();
+
+            if self.tx.send(batch_ready).await.is_err() {
+                warn!("Thread #{}: Writer stopped, dropping {count} events", self.thread_id);
+                self.writer.counters.lost(count);
+            }
+        }
+    }
+
+    // One PART per line for as many channels as fit, Twitch drops lines over 512 bytes
+    pub async fn part_all(&mut self, socket: &mut Socket) {
+        let thread_id = self.thread_id;
+        let mut lines: Vec<String> = Vec::new();
+
+        for channel in &self.channels {
+            match lines.last_mut() {
+                Some(line) if line.len() + channel.len() < 500 => {
+                    line.push(',');
+                    line.push_str(channel);
+                }
+                _ => lines.push(format!("PART {channel}")),
+            }
+        }
+
+        for line in lines {
+            if let Err(e) = self.send(socket, line).await {
+                warn!("Thread #{thread_id}: Error parting channels: {e}");
+                return;
+            }
+        }
+
+        info!("Thread #{thread_id}: Parted {} channels", self.channels.len());
+    }
+
+    pub fn log_status(&self) {
+        debug!(
+            "Thread #{}: Batch Size: {} Buffer Count: {} ({} events, {} queued) Latency: {:?}",
+            self.thread_id,
+            self.batch_size,
+            self.buffer.len(),
+            self.buffered_events(),
+            self.tx.queued(),
+            self.latency
+        );
+    }
+}
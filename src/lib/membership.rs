@@ -0,0 +1,28 @@
+use chrono::prelude::*;
+
+use super::irc::IrcMessage;
+
+// JOIN and PART lines from the twitch.tv/membership capability
+// https://dev.twitch.tv/docs/irc/capabilities/#membership-capability
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Membership {
+    pub channel: String,
+    pub command: String,
+    pub login: String,
+    // The bot's own JOIN and PART, which start and end its view of who is in the channel
+    #[serde(default)]
+    pub is_self: bool,
+    pub timestamp: chrono::DateTime<Utc>,
+}
+
+impl Membership {
+    pub fn from_irc(message: &IrcMessage) -> Self {
+        Self {
+            channel: message.channel().to_string(),
+            command: message.command.to_string(),
+            login: message.nick().to_string(),
+            is_self: false,
+            timestamp: Utc::now(),
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use chrono::prelude::*;
+
+use super::irc::IrcMessage;
+
+// https://dev.twitch.tv/docs/irc/commands/#clearchat
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClearChat {
+    pub channel: String,
+    pub target_login: String,
+    pub ban_duration: Option<i32>,
+    pub room_id: String,
+    pub target_user_id: String,
+    pub tags_raw: String,
+    pub tmi_sent_ts: String,
+    pub timestamp: chrono::DateTime<Utc>,
+}
+
+impl ClearChat {
+    // An empty target_login means the whole chat was cleared, a missing ban_duration with a
+    // target_login means the user was permanently banned
+    pub fn from_irc(message: &IrcMessage) -> Self {
+        Self {
+            channel: message.channel().to_string(),
+            target_login: message.trailing().to_string(),
+            ban_duration: message.tag("ban-duration").and_then(|x| x.parse::<i32>().ok()),
+            room_id: message.tag("room-id").unwrap_or_default().to_string(),
+            target_user_id: message.tag("target-user-id").unwrap_or_default().to_string(),
+            tags_raw: message.tags_raw(),
+            tmi_sent_ts: message.tag("tmi-sent-ts").unwrap_or_default().to_string(),
+            timestamp: Utc::now(),
+        }
+    }
+}
+
+// https://dev.twitch.tv/docs/irc/commands/#clearmsg
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClearMsg {
+    pub channel: String,
+    pub content: String,
+    pub login: String,
+    pub room_id: String,
+    pub tags_raw: String,
+    pub target_msg_id: String,
+    pub tmi_sent_ts: String,
+    pub timestamp: chrono::DateTime<Utc>,
+}
+
+impl ClearMsg {
+    pub fn from_irc(message: &IrcMessage) -> Self {
+        Self {
+            channel: message.channel().to_string(),
+            content: message.trailing().to_string(),
+            login: message.tag("login").unwrap_or_default().to_string(),
+            room_id: message.tag("room-id").unwrap_or_default().to_string(),
+            tags_raw: message.tags_raw(),
+            target_msg_id: message.tag("target-msg-id").unwrap_or_default().to_string(),
+            tmi_sent_ts: message.tag("tmi-sent-ts").unwrap_or_default().to_string(),
+            timestamp: Utc::now(),
+        }
+    }
+}
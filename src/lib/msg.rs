@@ -1,7 +1,8 @@
 use chrono::prelude::*;
-use regex::Regex;
 
-#[derive(Debug, Clone)]
+use super::irc::IrcMessage;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Msg {
     pub username: String,
     pub command: String,
@@ -11,36 +12,13 @@ pub struct Msg {
 }
 
 impl Msg {
-    pub fn new() -> Self {
+    pub fn from_irc(message: &IrcMessage) -> Self {
         Self {
-            username: String::new(),
-            command: String::new(),
-            channel: String::new(),
-            content: String::new(),
+            username: message.nick().to_string(),
+            command: message.command.to_string(),
+            channel: message.channel().to_string(),
+            content: message.trailing().to_string(),
             timestamp: Utc::now(),
         }
     }
-
-    pub fn parse_message(data: &str) -> Self {
-        lazy_static! {
-            static ref RE: Regex = {
-                let pattern = [
-                    r":(?P<username>\w*)!\w*@\w*.tmi.twitch.tv ",
-                    r"(?P<command>PRIVMSG) ",
-                    r"(?P<channel>#\w*) :(?P<content>.+)",
-                ]
-                .join("");
-
-                Regex::new(&pattern).unwrap()
-            };
-        }
-
-        RE.captures(data).map_or_else(Self::new, |msg| Self {
-            username: msg["username"].to_string(),
-            command: msg["command"].to_string(),
-            channel: msg["channel"].to_string(),
-            content: msg["content"].to_string().replace('\r', ""),
-            timestamp: Utc::now(),
-        })
-    }
 }
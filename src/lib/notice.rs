@@ -0,0 +1,42 @@
+use chrono::prelude::*;
+
+use super::irc::IrcMessage;
+
+// msg-id values that mean a JOIN was refused or the channel can no longer be read
+// https://dev.twitch.tv/docs/irc/msg-id/
+const JOIN_FAILURES: [&str; 6] = [
+    "msg_banned",
+    "msg_channel_blocked",
+    "msg_channel_suspended",
+    "msg_ratelimit",
+    "msg_room_not_found",
+    "tos_ban",
+];
+
+// https://dev.twitch.tv/docs/irc/commands/#notice
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Notice {
+    pub channel: String,
+    pub content: String,
+    pub notice_type: String,
+    pub target_user_id: String,
+    pub tags_raw: String,
+    pub timestamp: chrono::DateTime<Utc>,
+}
+
+impl Notice {
+    pub fn from_irc(message: &IrcMessage) -> Self {
+        Self {
+            channel: message.channel().to_string(),
+            content: message.trailing().to_string(),
+            notice_type: message.tag("msg-id").unwrap_or_default().to_string(),
+            target_user_id: message.tag("target-user-id").unwrap_or_default().to_string(),
+            tags_raw: message.tags_raw(),
+            timestamp: Utc::now(),
+        }
+    }
+
+    pub fn is_join_failure(&self) -> bool {
+        JOIN_FAILURES.contains(&self.notice_type.as_str())
+    }
+}
@@ -0,0 +1,70 @@
+use tokio::{
+    sync::Mutex,
+    time::{self, Duration, Instant},
+};
+
+use super::config;
+
+// One limiter per account, shared by all of its threads so reconnects on several connections at
+// once still stay within Twitch's limits
+// https://dev.twitch.tv/docs/irc/#rate-limits
+pub struct RateLimiter {
+    pub joins: TokenBucket,
+    pub commands: TokenBucket,
+}
+
+impl RateLimiter {
+    pub fn new(limits: &config::RateLimits) -> Self {
+        Self {
+            joins: TokenBucket::new(limits.joins, Duration::from_secs(limits.join_window_secs)),
+            commands: TokenBucket::new(
+                limits.commands,
+                Duration::from_secs(limits.command_window_secs),
+            ),
+        }
+    }
+}
+
+struct BucketState {
+    tokens: f64,
+    refilled: Instant,
+}
+
+// Starts full so a single connection can log in and join a burst of channels immediately
+pub struct TokenBucket {
+    capacity: f64,
+    per_sec: f64,
+    state: Mutex<BucketState>,
+}
+
+impl TokenBucket {
+    pub fn new(capacity: u32, window: Duration) -> Self {
+        let capacity = f64::from(capacity);
+
+        Self {
+            capacity,
+            per_sec: capacity / window.as_secs_f64(),
+            state: Mutex::new(BucketState { tokens: capacity, refilled: Instant::now() }),
+        }
+    }
+
+    // The lock is held while waiting, tokio's mutex is fair so callers are served in order
+    pub async fn acquire(&self) {
+        let mut state = self.state.lock().await;
+
+        loop {
+            let now = Instant::now();
+            let elapsed = now.duration_since(state.refilled).as_secs_f64();
+
+            state.tokens = (state.tokens + elapsed * self.per_sec).min(self.capacity);
+            state.refilled = now;
+
+            if state.tokens >= 1.0 {
+                state.tokens -= 1.0;
+                return;
+            }
+
+            time::sleep(Duration::from_secs_f64((1.0 - state.tokens) / self.per_sec)).await;
+        }
+    }
+}
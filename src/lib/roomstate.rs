@@ -0,0 +1,53 @@
+use chrono::prelude::*;
+
+use super::irc::IrcMessage;
+
+// https://dev.twitch.tv/docs/irc/commands/#roomstate
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoomState {
+    pub channel: String,
+    pub room_id: String,
+    pub emote_only: Option<i32>,
+    pub followers_only: Option<i32>,
+    pub r9k: Option<i32>,
+    pub slow: Option<i32>,
+    pub subs_only: Option<i32>,
+    pub tags_raw: String,
+    pub timestamp: chrono::DateTime<Utc>,
+}
+
+impl RoomState {
+    pub fn from_irc(message: &IrcMessage) -> Self {
+        let number = |name: &str| message.tag(name).and_then(|x| x.parse::<i32>().ok());
+
+        Self {
+            channel: message.channel().to_string(),
+            room_id: message.tag("room-id").unwrap_or_default().to_string(),
+            emote_only: number("emote-only"),
+            followers_only: number("followers-only"),
+            r9k: number("r9k"),
+            slow: number("slow"),
+            subs_only: number("subs-only"),
+            tags_raw: message.tags_raw(),
+            timestamp: Utc::now(),
+        }
+    }
+
+    // Twitch sends the full state on JOIN but only the changed setting afterwards, so fill in
+    // the rest from the last known state of the room
+    pub fn merge(&mut self, previous: &Self) {
+        self.emote_only = self.emote_only.or(previous.emote_only);
+        self.followers_only = self.followers_only.or(previous.followers_only);
+        self.r9k = self.r9k.or(previous.r9k);
+        self.slow = self.slow.or(previous.slow);
+        self.subs_only = self.subs_only.or(previous.subs_only);
+    }
+
+    pub fn same_modes(&self, other: &Self) -> bool {
+        self.emote_only == other.emote_only
+            && self.followers_only == other.followers_only
+            && self.r9k == other.r9k
+            && self.slow == other.slow
+            && self.subs_only == other.subs_only
+    }
+}
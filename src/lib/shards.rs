@@ -0,0 +1,821 @@
+use bb8::Pool;
+use bb8_postgres::PostgresConnectionManager;
+use futures_util::StreamExt;
+use log::{error, info, warn};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    mem,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc, oneshot, watch},
+    task::{JoinError, JoinHandle, JoinSet},
+    time::Instant,
+};
+use tokio_postgres::NoTls;
+use tokio_tungstenite::tungstenite::Message;
+
+use super::{backoff, config, db, error, listener, ratelimit, spool, writer};
+
+pub const HEALTH_CHECK: Duration = Duration::from_secs(10);
+const HANDOVER_TIMEOUT: Duration = Duration::from_secs(60);
+
+// The manager's side of a running thread: channel changes go in, health comes out, and
+// `shutdown` turns true once the process is stopping
+struct ThreadLink {
+    control: mpsc::UnboundedReceiver<listener::Control>,
+    health: watch::Sender<Option<Instant>>,
+    shutdown: watch::Receiver<bool>,
+}
+
+// Resolves once the process is stopping
+pub async fn stopping(shutdown: &mut watch::Receiver<bool>) {
+    // An error means the manager is gone, which only happens when the process is stopping
+    let _ = shutdown.wait_for(|stop| *stop).await;
+}
+
+// Runs one connection until it gives up or the process shuts down, then hands every pending
+// event to the writer and waits for it to finish. `health` holds the time the connection went
+// down, or None while it is logged in.
+async fn connect_and_listen(
+    config: config::Config,
+    writer: writer::Writer,
+    account: config::Account,
+    channels: Vec<String>,
+    limiter: Arc<ratelimit::RateLimiter>,
+    mut link: ThreadLink,
+    thread_id: u32,
+) -> Result<(), error::Error> {
+    let mut backoff = backoff::Backoff::new(&config.reconnect);
+    let (tx, rx) = writer::queue(config.backpressure.channel_capacity);
+    let mut listener =
+        listener::Listener::new(thread_id, config, account, channels, limiter, writer.clone(), tx);
+    let writer = tokio::spawn(writer.write_batches(rx));
+
+    let result = loop {
+        // Channel changes that arrived while disconnected are picked up by the next login
+        while let Ok(mut change) = link.control.try_recv() {
+            listener.update_channels(&mut change);
+        }
+
+        let socket = tokio::select! {
+            socket = listener::connect(&listener.config) => socket,
+            () = stopping(&mut link.shutdown) => break Ok(()),
+        };
+
+        match socket {
+            Ok(socket) => {
+                info!("Thread #{thread_id}: Connected to websocket server successfully");
+
+                let result = listen(&mut listener, socket, &mut backoff, &mut link).await;
+
+                if *link.shutdown.borrow() {
+                    break Ok(());
+                }
+
+                match result {
+                    Ok(()) => warn!("Thread #{thread_id}: Disconnected from websocket server"),
+                    Err(e @ error::Error::Auth(_)) => {
+                        error!(
+                            "Thread #{thread_id}: {e}. Check nickname and oauth of {} in config.json",
+                            listener.account.nickname
+                        );
+                        break Err(e);
+                    }
+                    Err(e) => {
+                        warn!("Thread #{thread_id}: Disconnected from websocket server: {e}");
+                    }
+                }
+            }
+            Err(e) => {
+                warn!("Thread #{thread_id}: Error connecting to websocket server: {e}");
+            }
+        }
+
+        link.health.send_if_modified(|since| {
+            if since.is_none() {
+                *since = Some(Instant::now());
+                return true;
+            }
+            false
+        });
+
+        let Some(delay) = backoff.next_delay() else {
+            warn!(
+                "Thread #{thread_id}: Failed to connect to websocket server in {} attempts",
+                backoff.attempts()
+            );
+            break Err(error::Error::ShardFailed(thread_id));
+        };
+
+        info!("Thread #{thread_id}: Reconnecting in {:.1} seconds...", delay.as_secs_f32());
+
+        tokio::select! {
+            () = tokio::time::sleep(delay) => {}
+            () = stopping(&mut link.shutdown) => break Ok(()),
+        }
+    };
+
+    listener.flush().await;
+    // Dropping the listener closes the channel, so the writer stops once the last batch is in
+    drop(listener);
+
+    if let Err(e) = writer.await {
+        warn!("Thread #{thread_id}: {e}");
+    }
+
+    result
+}
+
+// Logs in and reads from the socket until it disconnects or the process shuts down
+async fn listen(
+    listener: &mut listener::Listener,
+    mut socket: listener::Socket,
+    backoff: &mut backoff::Backoff,
+    link: &mut ThreadLink,
+) -> Result<(), error::Error> {
+    let mut housekeeping = tokio::time::interval(Duration::from_secs(1));
+
+    listener.login(&mut socket).await?;
+    backoff.reset();
+    // Healthy as soon as the login works, joins can take a while behind the rate limiter
+    link.health.send_replace(None);
+
+    tokio::select! {
+        () = listener.join_all(&mut socket) => {}
+        () = stopping(&mut link.shutdown) => return Ok(()),
+    }
+
+    loop {
+        tokio::select! {
+            data = socket.next() => {
+                let data = match data {
+                    Some(Ok(Message::Text(data))) => data,
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => return Err(e.into()),
+                    None => return Ok(()),
+                };
+
+                listener.log_status();
+
+                if listener.handle_frame(&mut socket, &data).await {
+                    listener.handover(&mut socket).await?;
+                }
+            }
+            Some(change) = link.control.recv() => {
+                listener.control(&mut socket, change).await?;
+            }
+            () = stopping(&mut link.shutdown) => {
+                listener.part_all(&mut socket).await;
+                socket.close(None).await?;
+                return Ok(());
+            }
+            _ = housekeeping.tick() => {
+                listener.check_joins(&mut socket).await;
+                listener.keepalive(&mut socket).await?;
+            }
+        }
+    }
+}
+
+// A connection's share of the channels before its thread is started
+struct Group {
+    account: usize,
+    channels: Vec<String>,
+}
+
+// Groups channels by thread, keeping each channel on its saved thread and account while they have
+// room. The remaining channels go to the accounts with the most room, and each account uses only
+// as many connections as the per-connection cap requires.
+fn assign(
+    channels: &[String],
+    saved: &HashMap<String, (u32, String)>,
+    accounts: &[config::Account],
+    per_connection: usize,
+    per_account: usize,
+) -> BTreeMap<u32, Group> {
+    let mut groups: BTreeMap<u32, Group> = BTreeMap::new();
+    let mut load = vec![0; accounts.len()];
+    let mut unassigned = Vec::new();
+
+    for channel in channels {
+        // With a single account, e.g. an anonymous justinfan, the saved nickname does not matter
+        let restored = saved.get(channel).and_then(|(thread_id, nickname)| {
+            let account = accounts
+                .iter()
+                .position(|x| &x.nickname == nickname)
+                .or((accounts.len() == 1).then_some(0))?;
+
+            Some((*thread_id, account))
+        });
+
+        if let Some((thread_id, account)) = restored {
+            let group =
+                groups.entry(thread_id).or_insert_with(|| Group { account, channels: Vec::new() });
+
+            if group.account == account
+                && group.channels.len() < per_connection
+                && load[account] < per_account
+            {
+                group.channels.push(channel.clone());
+                load[account] += 1;
+                continue;
+            }
+        }
+
+        unassigned.push(channel.clone());
+    }
+
+    groups.retain(|_, group| !group.channels.is_empty());
+
+    let mut by_account = vec![Vec::new(); accounts.len()];
+
+    for channel in unassigned {
+        match (0..accounts.len()).filter(|&i| load[i] < per_account).min_by_key(|&i| load[i]) {
+            Some(account) => {
+                load[account] += 1;
+                by_account[account].push(channel);
+            }
+            None => warn!("No account has room for {channel}, raise max_channels_per_account"),
+        }
+    }
+
+    for (account, channels) in by_account.into_iter().enumerate() {
+        while groups.values().filter(|group| group.account == account).count()
+            < load[account].div_ceil(per_connection)
+        {
+            let thread_id = (0..).find(|id| !groups.contains_key(id)).unwrap_or_default();
+
+            groups.insert(thread_id, Group { account, channels: Vec::new() });
+        }
+
+        for channel in channels {
+            if let Some(group) = groups
+                .values_mut()
+                .filter(|group| group.account == account && group.channels.len() < per_connection)
+                .min_by_key(|group| group.channels.len())
+            {
+                group.channels.push(channel);
+            }
+        }
+    }
+
+    groups
+}
+
+struct Shard {
+    thread_id: u32,
+    account: usize,
+    channels: Vec<String>,
+    control: mpsc::UnboundedSender<listener::Control>,
+    health: watch::Receiver<Option<Instant>>,
+}
+
+impl Shard {
+    // A thread that already stopped has its channels handed out again on the next reload
+    fn send(&self, control: listener::Control) {
+        if self.control.send(control).is_err() {
+            warn!("Thread #{}: Thread is no longer running", self.thread_id);
+        }
+    }
+
+    // Parts the channel once `joined` fires, or after HANDOVER_TIMEOUT if the other thread never
+    // confirms its JOIN, so the channel does not stay on both threads
+    fn part_after(&self, channel: String, joined: oneshot::Receiver<()>) {
+        let thread_id = self.thread_id;
+        let control = self.control.clone();
+
+        tokio::spawn(async move {
+            if tokio::time::timeout(HANDOVER_TIMEOUT, joined).await.is_err() {
+                warn!("Thread #{thread_id}: No JOIN confirmed for {channel}, parting it anyway");
+            }
+
+            // A thread that stopped meanwhile has nothing left to part
+            let _ = control.send(listener::Control::Part(channel));
+        });
+    }
+
+    fn is_running(&self) -> bool {
+        !self.control.is_closed()
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.is_running() && self.health.borrow().is_none()
+    }
+
+    fn down_for(&self) -> Option<Duration> {
+        self.health.borrow().map(|since| since.elapsed())
+    }
+}
+
+// Owns every connection thread and decides which account and connection joins each channel
+pub struct ShardManager {
+    // The settings loaded at startup, a reload only changes the channel list
+    config: config::Config,
+    writer: writer::Writer,
+    accounts: Vec<config::Account>,
+    // One per account, shared by all of its threads
+    limiters: Vec<Arc<ratelimit::RateLimiter>>,
+    channels_per_connection: usize,
+    max_channels_per_account: usize,
+    failover_after: Duration,
+    on_give_up: config::GiveUpPolicy,
+    shutdown_timeout: Duration,
+    shutdown: watch::Sender<bool>,
+    shards: Vec<Shard>,
+    threads: JoinSet<Result<(), error::Error>>,
+    drain: Option<JoinHandle<()>>,
+}
+
+impl ShardManager {
+    pub fn new(
+        pool: Pool<PostgresConnectionManager<NoTls>>,
+        config: &config::Config,
+        spool: spool::Spool,
+    ) -> Self {
+        let accounts = config.accounts();
+        let limiters = accounts
+            .iter()
+            .map(|account| {
+                Arc::new(ratelimit::RateLimiter::new(config.rate_limits(account.verified)))
+            })
+            .collect();
+        let shutdown = watch::channel(false).0;
+
+        Self {
+            config: config.clone(),
+            writer: writer::Writer::new(pool, config, spool, shutdown.subscribe()),
+            accounts,
+            limiters,
+            channels_per_connection: config.channels_per_connection,
+            max_channels_per_account: config.max_channels_per_account,
+            failover_after: Duration::from_secs(config.failover_after_secs),
+            on_give_up: config.reconnect.on_give_up,
+            shutdown_timeout: Duration::from_secs(config.shutdown_timeout_secs),
+            shutdown,
+            shards: Vec::new(),
+            threads: JoinSet::new(),
+            drain: None,
+        }
+    }
+
+    // Starts the threads with the layout from the previous run where it still fits
+    pub async fn start(&mut self, channels: &[String]) {
+        let saved = match db::load_assignments(&self.writer.pool).await {
+            Ok(saved) => saved,
+            Err(e) => {
+                warn!("Starting without saved shard assignments: {e}");
+                HashMap::new()
+            }
+        };
+        let groups = assign(
+            channels,
+            &saved,
+            &self.accounts,
+            self.channels_per_connection,
+            self.max_channels_per_account,
+        );
+
+        for (thread_id, group) in groups {
+            self.spawn(thread_id, group.account, group.channels);
+        }
+
+        self.save().await;
+        self.drain = Some(tokio::spawn(self.writer.clone().drain(self.shutdown.subscribe())));
+    }
+
+    pub async fn join_next(&mut self) -> Option<Result<Result<(), error::Error>, JoinError>> {
+        self.threads.join_next().await
+    }
+
+    fn spawn(&mut self, thread_id: u32, account: usize, channels: Vec<String>) {
+        let (control_tx, control_rx) = mpsc::unbounded_channel();
+        let (health_tx, health_rx) = watch::channel(Some(Instant::now()));
+
+        info!(
+            "Thread #{thread_id}: Starting with {} channels as {}",
+            channels.len(),
+            self.accounts[account].nickname
+        );
+
+        self.threads.spawn(connect_and_listen(
+            self.config.clone(),
+            self.writer.clone(),
+            self.accounts[account].clone(),
+            channels.clone(),
+            self.limiters[account].clone(),
+            ThreadLink {
+                control: control_rx,
+                health: health_tx,
+                shutdown: self.shutdown.subscribe(),
+            },
+            thread_id,
+        ));
+        self.shards.push(Shard {
+            thread_id,
+            account,
+            channels,
+            control: control_tx,
+            health: health_rx,
+        });
+    }
+
+    fn next_thread_id(&self) -> u32 {
+        self.shards.iter().map(|shard| shard.thread_id + 1).max().unwrap_or_default()
+    }
+
+    fn account_load(&self, account: usize) -> usize {
+        self.shards
+            .iter()
+            .filter(|shard| shard.account == account && shard.is_running())
+            .map(|shard| shard.channels.len())
+            .sum()
+    }
+
+    async fn save(&self) {
+        let assignments: Vec<(String, u32, String)> = self
+            .shards
+            .iter()
+            .filter(|shard| shard.is_running())
+            .flat_map(|shard| {
+                shard.channels.iter().map(|channel| {
+                    (
+                        channel.clone(),
+                        shard.thread_id,
+                        self.accounts[shard.account].nickname.clone(),
+                    )
+                })
+            })
+            .collect();
+
+        if let Err(e) = db::save_assignments(&self.writer.pool, &assignments).await {
+            warn!("Error saving shard assignments: {e}");
+        }
+    }
+
+    // Joins each channel on the account with the most room, on its least loaded thread with room
+    // (healthy ones only if asked), and starts more threads for whatever does not fit
+    fn place(&mut self, channels: Vec<String>, healthy_only: bool) {
+        let cap = self.channels_per_connection;
+        let mut load: Vec<usize> = (0..self.accounts.len()).map(|i| self.account_load(i)).collect();
+        let mut unassigned = vec![Vec::new(); self.accounts.len()];
+
+        for channel in channels {
+            let Some(account) = (0..self.accounts.len())
+                .filter(|&i| load[i] < self.max_channels_per_account)
+                .min_by_key(|&i| load[i])
+            else {
+                warn!("No account has room for {channel}, raise max_channels_per_account");
+                continue;
+            };
+            let shard = self
+                .shards
+                .iter_mut()
+                .filter(|shard| shard.account == account && shard.channels.len() < cap)
+                .filter(|shard| if healthy_only { shard.is_healthy() } else { shard.is_running() })
+                .min_by_key(|shard| shard.channels.len());
+
+            load[account] += 1;
+
+            match shard {
+                Some(shard) => {
+                    shard.channels.push(channel.clone());
+                    shard.send(listener::Control::Join(channel, None));
+                }
+                None => unassigned[account].push(channel),
+            }
+        }
+
+        for (account, channels) in unassigned.into_iter().enumerate() {
+            for chunk in channels.chunks(cap) {
+                self.spawn(self.next_thread_id(), account, chunk.to_vec());
+            }
+        }
+    }
+
+    // Parts removed channels, joins new ones on the least loaded threads and starts more threads
+    // once every running one is full
+    pub async fn reload(&mut self, channels: &[String]) {
+        self.shards.retain(Shard::is_running);
+
+        let current: HashSet<String> =
+            self.shards.iter().flat_map(|shard| shard.channels.iter().cloned()).collect();
+        let wanted: HashSet<&String> = channels.iter().collect();
+        let mut parted = 0;
+
+        for shard in &mut self.shards {
+            let (keep, part): (Vec<String>, Vec<String>) =
+                shard.channels.drain(..).partition(|channel| wanted.contains(channel));
+
+            shard.channels = keep;
+            parted += part.len();
+
+            for channel in part {
+                shard.send(listener::Control::Part(channel));
+            }
+        }
+
+        let added: Vec<String> =
+            channels.iter().filter(|channel| !current.contains(*channel)).cloned().collect();
+        let joined = added.len();
+
+        self.place(added, false);
+        self.rebalance();
+        self.save().await;
+
+        info!(
+            "Reloaded channels: {joined} joined, {parted} parted, {} threads running",
+            self.shards.len()
+        );
+    }
+
+    // Moves the channels of threads that have been down for too long to the healthy ones. The
+    // thread that was down keeps reconnecting and gets channels again once it is back.
+    pub async fn check_health(&mut self) {
+        // Nothing to gain if every connection is down, that is most likely our own network
+        if !self.shards.iter().any(Shard::is_healthy) {
+            return;
+        }
+
+        let mut moved = Vec::new();
+
+        for shard in &mut self.shards {
+            let Some(down_for) = shard.down_for() else {
+                continue;
+            };
+
+            if shard.is_running() && down_for >= self.failover_after && !shard.channels.is_empty() {
+                warn!(
+                    "Thread #{}: Down for {} seconds, moving {} channels to other threads",
+                    shard.thread_id,
+                    down_for.as_secs(),
+                    shard.channels.len()
+                );
+
+                for channel in &shard.channels {
+                    shard.send(listener::Control::Part(channel.clone()));
+                }
+
+                moved.append(&mut mem::take(&mut shard.channels));
+            }
+        }
+
+        let failed_over = !moved.is_empty();
+
+        self.place(moved, true);
+
+        if self.rebalance() || failed_over {
+            self.save().await;
+        }
+    }
+
+    // Moves channels from the fullest to the emptiest healthy thread until they differ by at most
+    // one, unless that would put the other account over its limit. The old thread only parts once
+    // the new one confirmed its JOIN, so the channel is not left unlogged in between.
+    fn rebalance(&mut self) -> bool {
+        let mut moved = false;
+
+        loop {
+            let healthy: Vec<usize> =
+                (0..self.shards.len()).filter(|&i| self.shards[i].is_healthy()).collect();
+            let Some(&fullest) = healthy.iter().max_by_key(|&&i| self.shards[i].channels.len())
+            else {
+                return moved;
+            };
+            let emptiest = healthy
+                .iter()
+                .copied()
+                .min_by_key(|&i| self.shards[i].channels.len())
+                .unwrap_or(fullest);
+
+            if self.shards[fullest].channels.len() <= self.shards[emptiest].channels.len() + 1 {
+                return moved;
+            }
+
+            let account = self.shards[emptiest].account;
+
+            if account != self.shards[fullest].account
+                && self.account_load(account) >= self.max_channels_per_account
+            {
+                return moved;
+            }
+
+            let Some(channel) = self.shards[fullest].channels.pop() else {
+                return moved;
+            };
+
+            let (joined_tx, joined_rx) = oneshot::channel();
+
+            self.shards[emptiest].channels.push(channel.clone());
+            self.shards[emptiest].send(listener::Control::Join(channel.clone(), Some(joined_tx)));
+            self.shards[fullest].part_after(channel, joined_rx);
+            moved = true;
+        }
+    }
+
+    // Stops every thread and waits until their pending events are stored or the timeout passes.
+    // Whatever is still pending at that point is reported as lost.
+    pub async fn shutdown(&mut self) {
+        let lost_before = self.writer.counters.lost_count();
+        let deadline = tokio::time::sleep(self.shutdown_timeout);
+
+        info!("Shutting down, storing {} pending events", self.writer.counters.pending_count());
+
+        self.shutdown.send_replace(true);
+
+        tokio::pin!(deadline);
+
+        loop {
+            tokio::select! {
+                result = self.threads.join_next() => match result {
+                    Some(Ok(Err(e))) => warn!("{e}"),
+                    Some(Err(e)) => warn!("{e}"),
+                    Some(Ok(Ok(()))) => {}
+                    None => break,
+                },
+                () = &mut deadline => {
+                    warn!(
+                        "Shutdown timed out after {} seconds",
+                        self.shutdown_timeout.as_secs()
+                    );
+                    self.threads.abort_all();
+                    break;
+                }
+            }
+        }
+
+        // The drain stops after the batch it is storing
+        if let Some(mut drain) = self.drain.take() {
+            tokio::select! {
+                _ = &mut drain => {}
+                () = &mut deadline => drain.abort(),
+            }
+        }
+
+        let lost =
+            self.writer.counters.pending_count() + self.writer.counters.lost_count() - lost_before;
+        let dropped = self.writer.counters.dropped_count();
+        let rejected = self.writer.counters.rejected_count();
+
+        if dropped > 0 {
+            warn!("{dropped} events were dropped from full buffers since startup");
+        }
+
+        if rejected > 0 {
+            warn!("Postgres rejected {rejected} events since startup");
+        }
+
+        if !self.writer.spool.is_empty() {
+            info!(
+                "{} bytes of events stay in the spool until the next start",
+                self.writer.spool.bytes()
+            );
+        }
+
+        if lost == 0 {
+            info!("Shutdown complete, all events were stored");
+        } else {
+            warn!("Shutdown complete, {lost} events were lost");
+        }
+    }
+
+    // Decides whether the process keeps running after a thread stopped
+    pub async fn finished(
+        &mut self,
+        result: Result<Result<(), error::Error>, JoinError>,
+    ) -> Result<(), error::Error> {
+        match result {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e @ error::Error::Auth(_))) => Err(e),
+            Ok(Err(e @ error::Error::ShardFailed(thread_id))) => match self.on_give_up {
+                config::GiveUpPolicy::Exit => {
+                    error!("{e}, exiting");
+                    Err(e)
+                }
+                config::GiveUpPolicy::Continue => {
+                    warn!("{e}, continuing with the remaining threads");
+                    Ok(())
+                }
+                config::GiveUpPolicy::Reassign => {
+                    warn!("{e}, moving its channels to the remaining threads");
+
+                    if let Some(i) = self.shards.iter().position(|x| x.thread_id == thread_id) {
+                        let shard = self.shards.remove(i);
+
+                        self.place(shard.channels, true);
+                        self.save().await;
+                    }
+
+                    Ok(())
+                }
+            },
+            Ok(Err(e)) => {
+                warn!("{e}");
+                Ok(())
+            }
+            Err(e) => {
+                warn!("{e}");
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accounts(nicknames: &[&str]) -> Vec<config::Account> {
+        nicknames
+            .iter()
+            .map(|nickname| config::Account {
+                nickname: nickname.to_string(),
+                oauth: "oauth:x".to_string(),
+                verified: false,
+            })
+            .collect()
+    }
+
+    fn channels(count: usize) -> Vec<String> {
+        (0..count).map(|i| format!("#c{i}")).collect()
+    }
+
+    fn saved(placements: &[(&str, u32, &str)]) -> HashMap<String, (u32, String)> {
+        placements
+            .iter()
+            .map(|(channel, thread_id, nickname)| {
+                (channel.to_string(), (*thread_id, nickname.to_string()))
+            })
+            .collect()
+    }
+
+    fn assigned(groups: &BTreeMap<u32, Group>) -> usize {
+        groups.values().map(|group| group.channels.len()).sum()
+    }
+
+    #[test]
+    fn keeps_saved_placements() {
+        let saved = saved(&[("#c0", 3, "a"), ("#c1", 3, "a"), ("#c2", 5, "b")]);
+        let groups = assign(&channels(4), &saved, &accounts(&["a", "b"]), 2, 100);
+
+        assert_eq!(groups[&3].account, 0);
+        assert_eq!(groups[&3].channels, ["#c0", "#c1"]);
+        assert_eq!(groups[&5].account, 1);
+        assert!(groups[&5].channels.contains(&"#c2".to_string()));
+        assert_eq!(assigned(&groups), 4);
+    }
+
+    #[test]
+    fn respects_per_connection() {
+        let groups = assign(&channels(5), &HashMap::new(), &accounts(&["a"]), 2, 100);
+
+        assert_eq!(groups.len(), 3);
+        assert!(groups.values().all(|group| group.channels.len() <= 2));
+        assert_eq!(assigned(&groups), 5);
+    }
+
+    #[test]
+    fn moves_saved_channels_over_the_cap() {
+        let saved = saved(&[("#c0", 0, "a"), ("#c1", 0, "a"), ("#c2", 0, "a")]);
+        let groups = assign(&channels(3), &saved, &accounts(&["a"]), 2, 100);
+
+        assert_eq!(groups[&0].channels, ["#c0", "#c1"]);
+        assert_eq!(groups[&1].channels, ["#c2"]);
+    }
+
+    #[test]
+    fn respects_per_account() {
+        let saved = saved(&[("#c0", 0, "a"), ("#c1", 0, "a"), ("#c2", 0, "a")]);
+        let groups = assign(&channels(5), &saved, &accounts(&["a", "b"]), 50, 2);
+        let load = |account| -> usize {
+            groups
+                .values()
+                .filter(|group| group.account == account)
+                .map(|group| group.channels.len())
+                .sum()
+        };
+
+        assert_eq!(groups[&0].channels, ["#c0", "#c1"]);
+        assert_eq!(load(0), 2);
+        assert_eq!(load(1), 2);
+        assert_eq!(assigned(&groups), 4);
+    }
+
+    #[test]
+    fn reassigns_channels_of_unknown_accounts() {
+        let saved = saved(&[("#c0", 7, "gone")]);
+        let groups = assign(&channels(1), &saved, &accounts(&["a", "b"]), 50, 100);
+
+        assert!(!groups.contains_key(&7));
+        assert_eq!(assigned(&groups), 1);
+    }
+
+    #[test]
+    fn single_account_keeps_saved_threads_under_a_new_nickname() {
+        let saved = saved(&[("#c0", 4, "justinfan12345")]);
+        let groups = assign(&channels(1), &saved, &accounts(&["justinfan67890"]), 50, 100);
+
+        assert_eq!(groups[&4].channels, ["#c0"]);
+    }
+}
@@ -0,0 +1,391 @@
+use log::{info, warn};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Seek, SeekFrom, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    task,
+};
+
+use super::{config, event};
+
+// Events that could not be handed to Postgres, one JSON object per line. New events are appended
+// to `path` while a drain works through `path.draining`, so spooling never waits for the drain.
+// Every file operation runs on the blocking pool, never on a thread that reads a connection.
+#[derive(Debug)]
+pub struct Spool {
+    config: config::SpoolConfig,
+    state: Mutex<State>,
+    // Size of both files together, checked against max_mb. Kept outside the lock so it can be
+    // read while a write or fsync is running.
+    bytes: AtomicU64,
+}
+
+#[derive(Debug)]
+struct State {
+    // None when the spool is disabled
+    file: Option<File>,
+    last_sync: Instant,
+}
+
+// A spool file being stored. `offset` is where the events that were not read yet start.
+pub struct Drain {
+    reader: BufReader<tokio::fs::File>,
+    offset: u64,
+}
+
+fn file_len(path: &str) -> u64 {
+    fs::metadata(path).map(|metadata| metadata.len()).unwrap_or_default()
+}
+
+impl Spool {
+    pub fn open(config: &config::SpoolConfig) -> Result<Self, io::Error> {
+        if !config.enabled {
+            return Ok(Self::disabled());
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(&config.path)?;
+        let bytes = file.metadata()?.len() + file_len(&draining_path(config));
+
+        if bytes > 0 {
+            info!("Spool {} holds {bytes} bytes of events from a previous run", config.path);
+        }
+
+        Ok(Self {
+            config: config.clone(),
+            state: Mutex::new(State { file: Some(file), last_sync: Instant::now() }),
+            bytes: AtomicU64::new(bytes),
+        })
+    }
+
+    pub fn disabled() -> Self {
+        Self {
+            config: config::SpoolConfig { enabled: false, ..Default::default() },
+            state: Mutex::new(State { file: None, last_sync: Instant::now() }),
+            bytes: AtomicU64::new(0),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes() == 0
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    // Returns false if the events could not be written, e.g. because the spool is full
+    pub async fn append(self: &Arc<Self>, events: &[event::Event]) -> bool {
+        if !self.config.enabled {
+            return false;
+        }
+
+        let mut data = Vec::new();
+
+        for event in events {
+            if let Err(e) = serde_json::to_writer(&mut data, event) {
+                warn!("Error serializing spooled event: {e}");
+                return false;
+            }
+
+            data.push(b'\n');
+        }
+
+        let spool = Arc::clone(self);
+
+        task::spawn_blocking(move || spool.write(&data)).await.unwrap_or(false)
+    }
+
+    fn write(&self, data: &[u8]) -> bool {
+        let mut state = self.state();
+        let state = &mut *state;
+        let Some(file) = state.file.as_mut() else {
+            return false;
+        };
+
+        if self.bytes() + data.len() as u64 > self.config.max_mb * 1024 * 1024 {
+            warn!("Spool {} is full", self.config.path);
+            return false;
+        }
+
+        // A partly written line is skipped when it is drained
+        if let Err(e) = file.write_all(data) {
+            warn!("Error writing to spool {}: {e}", self.config.path);
+            return false;
+        }
+
+        self.bytes.fetch_add(data.len() as u64, Ordering::Relaxed);
+
+        let sync = match self.config.fsync {
+            config::FsyncPolicy::Always => true,
+            config::FsyncPolicy::Interval => {
+                state.last_sync.elapsed() >= Duration::from_secs(self.config.fsync_interval_secs)
+            }
+            config::FsyncPolicy::Never => false,
+        };
+
+        if sync {
+            if let Err(e) = file.sync_data() {
+                warn!("Error syncing spool {}: {e}", self.config.path);
+            }
+
+            state.last_sync = Instant::now();
+        }
+
+        true
+    }
+
+    // Continues an unfinished drain, or moves the spooled events aside so they can be drained
+    // while new ones are appended
+    pub async fn take(self: &Arc<Self>) -> Option<Drain> {
+        if !self.config.enabled || self.is_empty() {
+            return None;
+        }
+
+        let spool = Arc::clone(self);
+        let draining = draining_path(&self.config);
+        let result = task::spawn_blocking(move || spool.rotate())
+            .await
+            .unwrap_or_else(|e| Err(io::Error::other(e)));
+
+        match result {
+            Ok(false) => None,
+            Ok(true) => match tokio::fs::File::open(&draining).await {
+                Ok(file) => Some(Drain { reader: BufReader::new(file), offset: 0 }),
+                Err(e) => {
+                    warn!("Error opening spool {draining}: {e}");
+                    None
+                }
+            },
+            Err(e) => {
+                warn!("Error opening spool {draining}: {e}");
+                None
+            }
+        }
+    }
+
+    // Returns false if there is nothing to drain
+    fn rotate(&self) -> Result<bool, io::Error> {
+        let draining = draining_path(&self.config);
+
+        if fs::metadata(&draining).is_ok() {
+            return Ok(true);
+        }
+
+        let mut state = self.state();
+        let Some(file) = state.file.as_mut() else {
+            return Ok(false);
+        };
+
+        if file.metadata()?.len() == 0 {
+            return Ok(false);
+        }
+
+        file.sync_data()?;
+        fs::rename(&self.config.path, &draining)?;
+        *file = OpenOptions::new().create(true).append(true).open(&self.config.path)?;
+
+        Ok(true)
+    }
+
+    // Deletes the drained file, or keeps only what was not read yet so the next drain starts
+    // there instead of storing the same events again. Appends go to the other file, so they do
+    // not wait for this.
+    pub async fn finish(self: &Arc<Self>, drain: Drain, complete: bool) {
+        let draining = draining_path(&self.config);
+        let path = draining.clone();
+        let offset = drain.offset;
+
+        drop(drain);
+
+        let result = task::spawn_blocking(move || {
+            let len = file_len(&path);
+
+            if complete {
+                fs::remove_file(&path).map(|()| len)
+            } else {
+                keep_from(&path, offset).map(|()| offset)
+            }
+        })
+        .await
+        .unwrap_or_else(|e| Err(io::Error::other(e)));
+
+        match result {
+            Ok(drained) => {
+                let _ = self.bytes.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bytes| {
+                    Some(bytes.saturating_sub(drained))
+                });
+            }
+            Err(e) => warn!("Error updating spool {draining}: {e}"),
+        }
+    }
+}
+
+impl Drain {
+    // Returns up to `size` events, or None at the end of the file
+    pub async fn next_batch(
+        &mut self,
+        size: usize,
+    ) -> Result<Option<Vec<event::Event>>, io::Error> {
+        let mut events = Vec::new();
+        let mut line = String::new();
+
+        while events.len() < size {
+            line.clear();
+
+            let read = self.reader.read_line(&mut line).await?;
+
+            if read == 0 {
+                break;
+            }
+
+            self.offset += read as u64;
+
+            match serde_json::from_str(&line) {
+                Ok(event) => events.push(event),
+                Err(e) => warn!("Skipping unreadable spooled event: {e}"),
+            }
+        }
+
+        Ok(if events.is_empty() { None } else { Some(events) })
+    }
+}
+
+fn draining_path(config: &config::SpoolConfig) -> String {
+    format!("{}.draining", config.path)
+}
+
+fn keep_from(path: &str, offset: u64) -> Result<(), io::Error> {
+    let temp = format!("{path}.tmp");
+    let mut file = File::open(path)?;
+    let mut rest = File::create(&temp)?;
+
+    file.seek(SeekFrom::Start(offset))?;
+    io::copy(&mut file, &mut rest)?;
+    rest.sync_data()?;
+    fs::rename(temp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::irc;
+    use std::path::PathBuf;
+
+    // A fresh directory per test, removed again by the test itself
+    fn spool_config(name: &str) -> (PathBuf, config::SpoolConfig) {
+        let dir = std::env::temp_dir()
+            .join(format!("twitch-log-bot-ws-spool-{name}-{}", std::process::id()));
+
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let config = config::SpoolConfig {
+            path: dir.join("spool.jsonl").to_string_lossy().into_owned(),
+            fsync: config::FsyncPolicy::Never,
+            ..Default::default()
+        };
+
+        (dir, config)
+    }
+
+    fn privmsg(content: &str) -> event::Event {
+        let line = format!(":ronni!ronni@ronni.tmi.twitch.tv PRIVMSG #ronni :{content}");
+
+        event::Event::from_irc(&irc::IrcMessage::parse(&line).unwrap()).unwrap()
+    }
+
+    fn contents(events: &[event::Event]) -> Vec<&str> {
+        events
+            .iter()
+            .map(|event| match event {
+                event::Event::Privmsg { msg, .. } => msg.content.as_str(),
+                _ => panic!("not a PRIVMSG: {event:?}"),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn partial_drain_resumes_where_it_stopped() {
+        let (dir, config) = spool_config("resume");
+        let spool = Arc::new(Spool::open(&config).unwrap());
+
+        assert!(spool.append(&[privmsg("a"), privmsg("b"), privmsg("c")]).await);
+
+        let mut drain = spool.take().await.unwrap();
+        let batch = drain.next_batch(2).await.unwrap().unwrap();
+
+        assert_eq!(contents(&batch), ["a", "b"]);
+        spool.finish(drain, false).await;
+
+        // Appended to the new file while the old one is only partly drained
+        assert!(spool.append(&[privmsg("d")]).await);
+
+        let mut drain = spool.take().await.unwrap();
+        let batch = drain.next_batch(10).await.unwrap().unwrap();
+
+        assert_eq!(contents(&batch), ["c"]);
+        assert!(drain.next_batch(10).await.unwrap().is_none());
+        spool.finish(drain, true).await;
+
+        let mut drain = spool.take().await.unwrap();
+        let batch = drain.next_batch(10).await.unwrap().unwrap();
+
+        assert_eq!(contents(&batch), ["d"]);
+        spool.finish(drain, true).await;
+
+        assert!(spool.is_empty());
+        assert!(spool.take().await.is_none());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_events_beyond_max_mb() {
+        let (dir, config) = spool_config("full");
+        let spool = Arc::new(Spool::open(&config::SpoolConfig { max_mb: 1, ..config }).unwrap());
+
+        assert!(!spool.append(&[privmsg(&"x".repeat(1024 * 1024))]).await);
+        assert!(spool.is_empty());
+        assert!(spool.append(&[privmsg("fits")]).await);
+        assert!(!spool.is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn skips_truncated_last_line() {
+        let (dir, config) = spool_config("truncated");
+        let spool = Arc::new(Spool::open(&config).unwrap());
+
+        assert!(spool.append(&[privmsg("a"), privmsg("b")]).await);
+        drop(spool);
+
+        // As left behind by a crash in the middle of a write
+        let mut file = OpenOptions::new().append(true).open(&config.path).unwrap();
+
+        file.write_all(br#"{"Privmsg":{"msg":{"username":"ro"#).unwrap();
+        drop(file);
+
+        let spool = Arc::new(Spool::open(&config).unwrap());
+        let mut drain = spool.take().await.unwrap();
+        let batch = drain.next_batch(10).await.unwrap().unwrap();
+
+        assert_eq!(contents(&batch), ["a", "b"]);
+        assert!(drain.next_batch(10).await.unwrap().is_none());
+        spool.finish(drain, true).await;
+        assert!(spool.is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,7 +1,6 @@
-use regex::Regex;
-use std::collections::HashMap;
+use super::irc::IrcMessage;
 
-#[derive(Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Tag {
     pub badge_info: String,
     pub badges: String,
@@ -63,34 +62,14 @@ impl Tag {
         }
     }
 
-    pub fn capture_tags(data: &str) -> String {
-        lazy_static! {
-            static ref RE: Regex = {
-                let pattern = r"@(?P<tags>(.+)) :\w*!\w*@\w*.tmi.twitch.tv .+";
-
-                Regex::new(pattern).unwrap()
-            };
-        }
-
-        RE.captures(data).map_or_else(String::new, |msg| msg["tags"].to_string())
-    }
-
     // https://dev.twitch.tv/docs/irc/tags
-    pub fn parse_tags(data: &str) -> Self {
-        let raw_tags = Self::capture_tags(data);
-        let raw_tags: Vec<&str> = raw_tags.split(';').collect();
-        let mut tags = HashMap::new();
-
-        if raw_tags.len() > 1 {
-            for tag in raw_tags {
-                let sep = tag.find('=').unwrap();
-                let tag_name = &tag[0..sep];
-                let tag_value = &tag[sep + 1..];
+    pub fn from_irc(message: &IrcMessage) -> Self {
+        let tags = &message.tags;
 
-                tags.insert(tag_name.to_string(), tag_value.to_string().replace(r"\s", " "));
-            }
-
-            let tags_raw = serde_json::to_string(&tags).unwrap();
+        if tags.is_empty() {
+            Self::new()
+        } else {
+            let tags_raw = message.tags_raw();
 
             Self {
                 badge_info: tags
@@ -148,8 +127,6 @@ impl Tag {
                     .map_or(String::new(), std::string::ToString::to_string),
                 vip: tags.get("vip").map_or(String::new(), std::string::ToString::to_string),
             }
-        } else {
-            Self::new()
         }
     }
 }
@@ -0,0 +1,85 @@
+use chrono::prelude::*;
+
+use super::irc::IrcMessage;
+
+// https://dev.twitch.tv/docs/irc/commands/#usernotice
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UserNotice {
+    pub channel: String,
+    pub content: String,
+    pub badges: String,
+    pub color: String,
+    pub display_name: String,
+    pub id: String,
+    pub login: String,
+    pub notice_type: String,
+    pub room_id: String,
+    pub system_msg: String,
+    pub user_id: String,
+    pub cumulative_months: Option<i32>,
+    pub gift_months: Option<i32>,
+    pub mass_gift_count: Option<i32>,
+    pub months: Option<i32>,
+    pub raid_display_name: String,
+    pub raid_login: String,
+    pub raid_viewer_count: Option<i32>,
+    pub recipient_display_name: String,
+    pub recipient_id: String,
+    pub recipient_user_name: String,
+    pub sender_count: Option<i32>,
+    pub should_share_streak: Option<i32>,
+    pub streak_months: Option<i32>,
+    pub sub_plan: String,
+    pub sub_plan_name: String,
+    pub msg_params: serde_json::Value,
+    pub tags_raw: String,
+    pub tmi_sent_ts: String,
+    pub timestamp: chrono::DateTime<Utc>,
+}
+
+impl UserNotice {
+    pub fn from_irc(message: &IrcMessage) -> Self {
+        let tag = |name: &str| message.tag(name).unwrap_or_default().to_string();
+        let number = |name: &str| message.tag(name).and_then(|x| x.parse::<i32>().ok());
+        let msg_params: serde_json::Map<String, serde_json::Value> = message
+            .tags
+            .iter()
+            .filter_map(|(k, v)| {
+                k.strip_prefix("msg-param-").map(|k| (k.to_string(), v.clone().into()))
+            })
+            .collect();
+
+        Self {
+            channel: message.channel().to_string(),
+            content: message.trailing().to_string(),
+            badges: tag("badges"),
+            color: tag("color"),
+            display_name: tag("display-name"),
+            id: tag("id"),
+            login: tag("login"),
+            notice_type: tag("msg-id"),
+            room_id: tag("room-id"),
+            system_msg: tag("system-msg"),
+            user_id: tag("user-id"),
+            cumulative_months: number("msg-param-cumulative-months"),
+            gift_months: number("msg-param-gift-months"),
+            mass_gift_count: number("msg-param-mass-gift-count"),
+            months: number("msg-param-months"),
+            raid_display_name: tag("msg-param-displayName"),
+            raid_login: tag("msg-param-login"),
+            raid_viewer_count: number("msg-param-viewerCount"),
+            recipient_display_name: tag("msg-param-recipient-display-name"),
+            recipient_id: tag("msg-param-recipient-id"),
+            recipient_user_name: tag("msg-param-recipient-user-name"),
+            sender_count: number("msg-param-sender-count"),
+            should_share_streak: number("msg-param-should-share-streak"),
+            streak_months: number("msg-param-streak-months"),
+            sub_plan: tag("msg-param-sub-plan"),
+            sub_plan_name: tag("msg-param-sub-plan-name"),
+            msg_params: msg_params.into(),
+            tags_raw: message.tags_raw(),
+            tmi_sent_ts: tag("tmi-sent-ts"),
+            timestamp: Utc::now(),
+        }
+    }
+}
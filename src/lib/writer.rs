@@ -0,0 +1,296 @@
+use bb8::Pool;
+use bb8_postgres::PostgresConnectionManager;
+use log::{debug, info, warn};
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::{
+        mpsc::{
+            self,
+            error::{SendError, TrySendError},
+        },
+        watch, Semaphore,
+    },
+    task::JoinSet,
+};
+use tokio_postgres::NoTls;
+
+use super::{backoff, config, counters, db, error, event, shards, spool};
+
+const DRAIN_INTERVAL: Duration = Duration::from_secs(5);
+
+// A connection's queue of batches to its writer. It counts the events that were sent but not
+// stored or spooled yet, so the listener can hold them against max_buffered_events.
+pub fn queue(capacity: usize) -> (BatchSender, BatchReceiver) {
+    let (tx, rx) = mpsc::channel(capacity);
+    let queued = Arc::new(AtomicUsize::new(0));
+
+    (BatchSender { tx, queued: queued.clone() }, BatchReceiver { rx, queued })
+}
+
+pub struct BatchSender {
+    tx: mpsc::Sender<Vec<event::Event>>,
+    queued: Arc<AtomicUsize>,
+}
+
+pub struct BatchReceiver {
+    rx: mpsc::Receiver<Vec<event::Event>>,
+    queued: Arc<AtomicUsize>,
+}
+
+impl BatchSender {
+    pub fn try_send(
+        &self,
+        batch: Vec<event::Event>,
+    ) -> Result<(), TrySendError<Vec<event::Event>>> {
+        let count = batch.len();
+
+        // Counted first, so the receiver never subtracts events that were not added yet
+        self.queued.fetch_add(count, Ordering::Relaxed);
+        self.tx.try_send(batch).inspect_err(|_| {
+            self.queued.fetch_sub(count, Ordering::Relaxed);
+        })
+    }
+
+    pub async fn send(&self, batch: Vec<event::Event>) -> Result<(), SendError<Vec<event::Event>>> {
+        let count = batch.len();
+
+        self.queued.fetch_add(count, Ordering::Relaxed);
+        self.tx.send(batch).await.inspect_err(|_| {
+            self.queued.fetch_sub(count, Ordering::Relaxed);
+        })
+    }
+
+    // Events waiting in the queue or being written
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+}
+
+impl BatchReceiver {
+    fn done(&self, count: usize) {
+        self.queued.fetch_sub(count, Ordering::Relaxed);
+    }
+}
+
+// Everything a thread needs to store its events. Clones share the same counters and spool.
+#[derive(Clone)]
+pub struct Writer {
+    pub pool: Pool<PostgresConnectionManager<NoTls>>,
+    pub counters: Arc<counters::EventCounters>,
+    pub spool: Arc<spool::Spool>,
+    // Set by the first error that is not about the data and cleared by the next successful write
+    unreachable: Arc<AtomicBool>,
+    method: config::InsertMethod,
+    // Spooled events are read back in batches of this size
+    batch_size: usize,
+    // Inserts a connection runs at once, further batches wait in its queue
+    concurrent_writes: usize,
+    retry: config::WriteRetryConfig,
+    // Once the process is stopping, batches go to the spool instead of being retried
+    shutdown: watch::Receiver<bool>,
+}
+
+impl Writer {
+    pub fn new(
+        pool: Pool<PostgresConnectionManager<NoTls>>,
+        config: &config::Config,
+        spool: spool::Spool,
+        shutdown: watch::Receiver<bool>,
+    ) -> Self {
+        Self {
+            pool,
+            counters: Arc::new(counters::EventCounters::new()),
+            spool: Arc::new(spool),
+            unreachable: Arc::new(AtomicBool::new(false)),
+            method: config.insert_method,
+            batch_size: config.backpressure.max_batch_size,
+            concurrent_writes: config.backpressure.max_concurrent_writes,
+            retry: config.write_retry.clone(),
+            shutdown,
+        }
+    }
+
+    // Retries a batch with backoff as long as the error may go away on its own
+    async fn insert_with_retry(&self, events: &[event::Event]) -> Result<(), error::Error> {
+        let mut backoff = backoff::Backoff::for_writes(&self.retry);
+
+        loop {
+            let insert = db::insert_data(self.pool.clone(), events, self.method);
+            let mut shutdown = self.shutdown.clone();
+
+            // While Postgres is down an attempt can wait for a connection longer than shutdown
+            // allows, so give up on it and let the batch go to the spool
+            let result = if self.unreachable.load(Ordering::Relaxed) {
+                tokio::select! {
+                    result = insert => result,
+                    () = shards::stopping(&mut shutdown) => Err(bb8::RunError::TimedOut.into()),
+                }
+            } else {
+                insert.await
+            };
+
+            let unusable = result.as_ref().is_err_and(|e| !db::is_data_error(e));
+
+            self.unreachable.store(unusable, Ordering::Relaxed);
+
+            match result {
+                Err(e) if db::is_transient(&e) => {
+                    let stopping = *self.shutdown.borrow();
+                    let Some(delay) = backoff.next_delay().filter(|_| !stopping) else {
+                        return Err(e);
+                    };
+
+                    warn!("{e}, retrying {} events in {delay:?}", events.len());
+
+                    tokio::select! {
+                        () = tokio::time::sleep(delay) => {}
+                        () = shards::stopping(&mut shutdown) => return Err(e),
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // Returns false if the spool is disabled or could not take the events
+    pub async fn spill(&self, events: &[event::Event]) -> bool {
+        if self.spool.append(events).await {
+            self.counters.spooled(events.len());
+            true
+        } else {
+            false
+        }
+    }
+
+    // Stores a batch, splitting it in halves whenever Postgres refuses its data until the events
+    // it will not take are found, so one bad event does not cost the whole batch. Returns false
+    // if Postgres stayed unreachable or cannot store anything right now, e.g. because its disk is
+    // full. The events that were not stored then go to the spool.
+    async fn store(&self, events: &[event::Event]) -> bool {
+        let mut parts = vec![events];
+
+        while let Some(part) = parts.pop() {
+            match self.insert_with_retry(part).await {
+                Ok(()) => self.counters.stored(part.len()),
+                Err(e) if db::is_data_error(&e) && part.len() == 1 => {
+                    warn!("Postgres rejected {:?}: {e}", part[0]);
+                    self.counters.rejected(1);
+                }
+                Err(e) if db::is_data_error(&e) => {
+                    debug!("Splitting a batch of {} events: {e}", part.len());
+                    let (first, second) = part.split_at(part.len() / 2);
+                    parts.push(second);
+                    parts.push(first);
+                }
+                Err(e) => {
+                    if !db::is_transient(&e) {
+                        warn!("Postgres cannot store events: {e}");
+                    }
+
+                    parts.push(part);
+
+                    for part in parts {
+                        if !self.spill(part).await {
+                            warn!("Giving up on {} events: {e}", part.len());
+                            self.counters.lost(part.len());
+                        }
+                    }
+
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+
+    // Inserts up to concurrent_writes batches at once until the listener is dropped, then waits
+    // for the inserts that are still running. While all of them are busy the queue fills up and
+    // the listener has to buffer, so a slow Postgres pushes back instead of piling up inserts.
+    pub async fn write_batches(self, mut rx: BatchReceiver) {
+        let mut inserts = JoinSet::new();
+        let permits = Arc::new(Semaphore::new(self.concurrent_writes));
+
+        loop {
+            let Ok(permit) = permits.clone().acquire_owned().await else {
+                break;
+            };
+            let Some(batch_ready) = rx.rx.recv().await else {
+                break;
+            };
+            let count = batch_ready.len();
+
+            // Spool instead of retrying while Postgres is down. Once it is back, new batches are
+            // written directly while the drain catches up with the spooled ones.
+            if self.unreachable.load(Ordering::Relaxed) && self.spill(&batch_ready).await {
+                rx.done(count);
+                continue;
+            }
+
+            let writer = self.clone();
+            let queued = rx.queued.clone();
+
+            inserts.spawn(async move {
+                writer.store(&batch_ready).await;
+                queued.fetch_sub(count, Ordering::Relaxed);
+                drop(permit);
+            });
+
+            while inserts.try_join_next().is_some() {}
+        }
+
+        while inserts.join_next().await.is_some() {}
+    }
+
+    // Stores spooled events at startup and whenever Postgres is reachable again. A drain that is
+    // cut short keeps its place, but a crash in the middle of a batch stores that batch twice.
+    pub async fn drain(self, mut shutdown: watch::Receiver<bool>) {
+        let mut interval = tokio::time::interval(DRAIN_INTERVAL);
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                () = shards::stopping(&mut shutdown) => return,
+            }
+
+            let Some(mut drain) = self.spool.take().await else {
+                continue;
+            };
+            let mut drained = 0;
+
+            let complete = loop {
+                if *shutdown.borrow() {
+                    break false;
+                }
+
+                match drain.next_batch(self.batch_size).await {
+                    Ok(Some(events)) => {
+                        self.counters.received(events.len());
+                        drained += events.len();
+
+                        if !self.store(&events).await {
+                            break false;
+                        }
+                    }
+                    Ok(None) => break true,
+                    Err(e) => {
+                        warn!("Error reading spool: {e}");
+                        break false;
+                    }
+                }
+            };
+
+            self.spool.finish(drain, complete).await;
+
+            if drained > 0 {
+                info!("Drained {drained} events from the spool");
+            }
+        }
+    }
+}